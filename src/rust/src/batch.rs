@@ -0,0 +1,173 @@
+// The draw commands of the same kind are squashed into one draw call. But, R
+// draws different kinds of primitives alternately (e.g. points and their
+// labels), so squashing only the adjacent commands would end up with a lot of
+// tiny draw calls. Instead, a new primitive is merged into the last command of
+// the same kind, as long as it doesn't overlap with anything drawn after that
+// command. The result is the same as drawing in the painter's order, including
+// transparency. The primitives are never moved across the clipping changes.
+
+use glam::f32::Affine2;
+
+use crate::graphics_device::WgpugdCommand;
+
+// How many commands to look back for the same kind of command. The commands
+// are merged, so the queue usually doesn't get this long between clippings.
+const MAX_LOOKBACK: usize = 32;
+
+// The bounding box of primitives in the device coordinates
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct BoundingBox {
+    pub(crate) min: [f32; 2],
+    pub(crate) max: [f32; 2],
+}
+
+impl BoundingBox {
+    // This overlaps with nothing.
+    pub(crate) const EMPTY: Self = Self {
+        min: [f32::INFINITY, f32::INFINITY],
+        max: [f32::NEG_INFINITY, f32::NEG_INFINITY],
+    };
+
+    pub(crate) fn from_points<T: IntoIterator<Item = (f32, f32)>>(points: T) -> Self {
+        points.into_iter().fold(Self::EMPTY, |bbox, (x, y)| Self {
+            min: [bbox.min[0].min(x), bbox.min[1].min(y)],
+            max: [bbox.max[0].max(x), bbox.max[1].max(y)],
+        })
+    }
+
+    pub(crate) fn expand(self, margin: f32) -> Self {
+        Self {
+            min: [self.min[0] - margin, self.min[1] - margin],
+            max: [self.max[0] + margin, self.max[1] + margin],
+        }
+    }
+
+    pub(crate) fn union(self, other: Self) -> Self {
+        Self {
+            min: [self.min[0].min(other.min[0]), self.min[1].min(other.min[1])],
+            max: [self.max[0].max(other.max[0]), self.max[1].max(other.max[1])],
+        }
+    }
+
+    pub(crate) fn intersects(&self, other: &Self) -> bool {
+        self.min[0] <= other.max[0]
+            && other.min[0] <= self.max[0]
+            && self.min[1] <= other.max[1]
+            && other.min[1] <= self.max[1]
+    }
+
+    // The bounding box of the transformed box
+    pub(crate) fn transform(self, transform: Affine2) -> Self {
+        if self == Self::EMPTY {
+            return self;
+        }
+
+        Self::from_points(
+            [
+                (self.min[0], self.min[1]),
+                (self.max[0], self.min[1]),
+                (self.min[0], self.max[1]),
+                (self.max[0], self.max[1]),
+            ]
+            .into_iter()
+            .map(|(x, y)| transform.transform_point2(glam::vec2(x, y)).into()),
+        )
+    }
+}
+
+// Returns the index of the command which the new command can be merged into.
+pub(crate) fn find_batch(queue: &[WgpugdCommand], new: &WgpugdCommand) -> Option<usize> {
+    let bbox = new.draw_command()?.bbox;
+
+    for (i, cmd) in queue.iter().enumerate().rev().take(MAX_LOOKBACK) {
+        if std::mem::discriminant(cmd) == std::mem::discriminant(new) {
+            return Some(i);
+        }
+
+        match cmd.draw_command() {
+            // Drawn after the command found, so it must not overlap.
+            Some(cmd) if !cmd.bbox.intersects(&bbox) => continue,
+            // Either overlapping or a clipping change
+            _ => return None,
+        }
+    }
+
+    None
+}
+
+// Merges the command into the existing one if possible, or pushes it.
+pub(crate) fn push_command(queue: &mut Vec<WgpugdCommand>, new: WgpugdCommand) {
+    match (find_batch(queue, &new), new.draw_command()) {
+        (Some(i), Some(new_cmd)) => {
+            if let Some(cmd) = queue[i].draw_command_mut() {
+                cmd.merge(new_cmd);
+            }
+        }
+        _ => queue.push(new),
+    }
+}
+
+#[test]
+fn test_push_command() {
+    use crate::graphics_device::DrawCommand;
+
+    let square = |x: f32, y: f32| BoundingBox {
+        min: [x, y],
+        max: [x + 1.0, y + 1.0],
+    };
+    let sdf = |x, y| WgpugdCommand::DrawSDF(DrawCommand::new(1, square(x, y)));
+    let polygon = |x, y| WgpugdCommand::DrawPolygon(DrawCommand::new(1, square(x, y)));
+    let counts = |queue: &[WgpugdCommand]| -> Vec<u32> {
+        queue
+            .iter()
+            .map(|cmd| cmd.draw_command().map(|cmd| cmd.count).unwrap_or(0))
+            .collect()
+    };
+
+    let mut queue = Vec::new();
+
+    // Alternating SDFs and polygons that don't overlap
+    for i in 0..10 {
+        push_command(&mut queue, sdf(i as f32 * 10.0, 0.0));
+        push_command(&mut queue, polygon(i as f32 * 10.0 + 5.0, 0.0));
+    }
+    assert_eq!(counts(&queue), vec![10, 10]);
+
+    // An SDF overlapping with a polygon drawn after the last SDF
+    push_command(&mut queue, sdf(95.5, 0.5));
+    assert_eq!(counts(&queue), vec![10, 10, 1]);
+
+    // A polygon overlapping with the SDF drawn just now
+    push_command(&mut queue, polygon(95.0, 0.0));
+    assert_eq!(counts(&queue), vec![10, 10, 1, 1]);
+
+    // Clipping is a barrier
+    queue.push(WgpugdCommand::SetClipping {
+        x: 0,
+        y: 0,
+        width: 100,
+        height: 100,
+        clip_rect: None,
+    });
+    push_command(&mut queue, sdf(200.0, 200.0));
+    assert_eq!(counts(&queue), vec![10, 10, 1, 1, 0, 1]);
+}
+
+#[test]
+fn test_bounding_box() {
+    let bbox = BoundingBox::from_points([(1.0, 2.0), (3.0, -1.0), (2.0, 0.0)]);
+    assert_eq!(
+        bbox,
+        BoundingBox {
+            min: [1.0, -1.0],
+            max: [3.0, 2.0]
+        }
+    );
+
+    assert!(bbox.intersects(&bbox.expand(1.0)));
+    assert!(!bbox.intersects(&BoundingBox::EMPTY));
+
+    let rotated = bbox.transform(Affine2::from_angle(std::f32::consts::FRAC_PI_2));
+    assert!((rotated.min[0] - -2.0).abs() < 1e-5);
+    assert!((rotated.max[1] - 3.0).abs() < 1e-5);
+}
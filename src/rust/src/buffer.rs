@@ -0,0 +1,64 @@
+use crate::stats;
+
+// A GPU buffer that is kept across pages and recreated in a larger size only
+// when the data doesn't fit.
+pub(crate) struct GrowableBuffer {
+    pub(crate) buffer: wgpu::Buffer,
+    size: u64,
+    label: &'static str,
+    usage: wgpu::BufferUsages,
+}
+
+impl GrowableBuffer {
+    pub(crate) fn new(
+        device: &wgpu::Device,
+        label: &'static str,
+        usage: wgpu::BufferUsages,
+        initial_size: u64,
+    ) -> Self {
+        // The data is written via `Queue::write_buffer()`, so `COPY_DST` is needed.
+        let usage = usage | wgpu::BufferUsages::COPY_DST;
+        Self {
+            buffer: create_buffer(device, label, usage, initial_size),
+            size: initial_size,
+            label,
+            usage,
+        }
+    }
+
+    // Make sure the buffer has at least `size` bytes. Returns true if the
+    // buffer is recreated, which means the data written so far is lost.
+    pub(crate) fn reserve(&mut self, device: &wgpu::Device, size: u64) -> bool {
+        if size <= self.size {
+            return false;
+        }
+
+        self.size = size.next_power_of_two();
+        self.buffer = create_buffer(device, self.label, self.usage, self.size);
+        true
+    }
+
+    // Write the data from the beginning of the buffer.
+    pub(crate) fn write(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, data: &[u8]) {
+        self.reserve(device, data.len() as _);
+        if !data.is_empty() {
+            queue.write_buffer(&self.buffer, 0, data);
+        }
+    }
+}
+
+fn create_buffer(
+    device: &wgpu::Device,
+    label: &str,
+    usage: wgpu::BufferUsages,
+    size: u64,
+) -> wgpu::Buffer {
+    stats::BUFFER_ALLOCATIONS.incr();
+
+    device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some(label),
+        size,
+        usage,
+        mapped_at_creation: false,
+    })
+}
@@ -0,0 +1,117 @@
+// The scissor of the render pass can clip only at integer pixels, so a clip
+// rect with fractional bounds (e.g. the panels of facets) gets hard edges off
+// by up to a pixel. Such a clip rect is applied in the shaders instead, with
+// the edges anti-aliased, while the scissor is set to the pixels it touches.
+//
+// The clip rects are in a uniform buffer, and selected by the dynamic offset
+// of the globals bind group.
+
+// The clip rect in the pixels of the framebuffer, with the Y-axis downward as
+// the fragment coordinates.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, bytemuck::Pod, bytemuck::Zeroable)]
+pub(crate) struct ClipRect {
+    // left, top, right, bottom
+    pub(crate) bounds: [f32; 4],
+}
+
+impl ClipRect {
+    // The clip rect for the commands clipped only by the scissor
+    pub(crate) const UNBOUNDED: ClipRect = ClipRect {
+        bounds: [f32::MIN, f32::MIN, f32::MAX, f32::MAX],
+    };
+}
+
+// The dynamic offsets must be aligned to `min_uniform_buffer_offset_alignment`,
+// which is at most 256.
+pub(crate) const CLIP_RECT_STRIDE: u64 = 256;
+
+// The scissor rect (x, y, width, height) and the clip rect for the shaders, if
+// needed, in the pixels of the framebuffer from the clip region of R (in
+// points, with the Y-axis upward). `scale` is the pixels of the framebuffer per
+// point.
+pub(crate) fn clip_region(
+    from: (f64, f64),
+    to: (f64, f64),
+    width: u32,
+    height: u32,
+    scale: u32,
+    exact: bool,
+) -> ([u32; 4], Option<ClipRect>) {
+    let x0 = from.0.clamp(0.0, width as _);
+    let x1 = to.0.clamp(0.0, width as _);
+    let y0 = from.1.clamp(0.0, height as _);
+    let y1 = to.1.clamp(0.0, height as _);
+
+    // Y-axis is upside down
+    let scale = scale as f64;
+    let (left, right) = (x0.min(x1) * scale, x0.max(x1) * scale);
+    let (top, bottom) = (
+        (height as f64 - y0.max(y1)) * scale,
+        (height as f64 - y0.min(y1)) * scale,
+    );
+
+    let is_aligned = [left, top, right, bottom]
+        .iter()
+        .all(|v| (v - v.round()).abs() < 1e-3);
+
+    if !exact || is_aligned {
+        let scissor = [
+            left as u32,
+            top as u32,
+            (right - left) as u32,
+            (bottom - top) as u32,
+        ];
+        return (scissor, None);
+    }
+
+    // The pixels touched by the clip rect
+    let (scissor_left, scissor_top) = (left.floor() as u32, top.floor() as u32);
+    let scissor = [
+        scissor_left,
+        scissor_top,
+        right.ceil() as u32 - scissor_left,
+        bottom.ceil() as u32 - scissor_top,
+    ];
+
+    let clip_rect = ClipRect {
+        bounds: [left as f32, top as f32, right as f32, bottom as f32],
+    };
+
+    (scissor, Some(clip_rect))
+}
+
+#[test]
+fn test_clip_region() {
+    // On the pixel boundaries, only the scissor
+    assert_eq!(
+        clip_region((10.0, 20.0), (50.0, 90.0), 100, 100, 1, true),
+        ([10, 10, 40, 70], None)
+    );
+    // Without the exact clipping, truncated as before
+    assert_eq!(
+        clip_region((10.5, 20.0), (50.5, 90.0), 100, 100, 1, false),
+        ([10, 10, 40, 70], None)
+    );
+
+    let (scissor, clip_rect) = clip_region((10.5, 20.25), (50.5, 90.0), 100, 100, 2, true);
+    assert_eq!(scissor, [21, 20, 80, 140]);
+    assert_eq!(
+        clip_rect,
+        Some(ClipRect {
+            bounds: [21.0, 20.0, 101.0, 159.5]
+        })
+    );
+
+    // Half a point is a whole pixel on supersampling
+    assert_eq!(
+        clip_region((10.5, 20.5), (50.5, 90.0), 100, 100, 2, true),
+        ([21, 20, 80, 139], None)
+    );
+
+    // Clamped to the device
+    assert_eq!(
+        clip_region((-5.0, -5.0), (100.5, 100.5), 100, 100, 1, true),
+        ([0, 0, 100, 100], None)
+    );
+}
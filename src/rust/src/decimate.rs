@@ -0,0 +1,278 @@
+// A polyline with far more points than the columns of pixels (e.g. a long time
+// series) can be decimated without changing the pixels its center line passes
+// through; within a column of pixels, the path covers the range between the
+// minimum and the maximum, so only the first, the minimum, the maximum, and the
+// last points of each run of points in the same column matter (a.k.a. M4
+// aggregation).
+//
+// Note that this is about the center line. The stroke has a width, and its
+// anti-aliased edges are not pixel-identical to the original. Without
+// decimation, the many overlapping segments of a dense polyline add up their
+// partial coverage, which darkens the edges. So, the decimated stroke is
+// lighter on the edges, but never noticeably darker. On the series in
+// `test_decimate_polyline_image()`, a pixel gets darker by at most 0.06 of the
+// full ink, and the mean difference over the image is at most 0.07. These are
+// measured with a CPU model of stroke.wgsl (the same coverage and blending,
+// rounded to 8 bits on every draw).
+//
+// ref: Jugel, U., Jerzak, Z., Hackenbroich, G., & Markl, V. (2014). M4: A
+// Visualization-Oriented Time Series Data Aggregation. Proc. VLDB Endow., 7,
+// 797-808.
+
+// Decimate only when there are this many points per column on average, as
+// it's not worth otherwise.
+const MIN_POINTS_PER_COLUMN: f64 = 4.0;
+
+// Returns `None` if the polyline is not dense enough to decimate.
+pub(crate) fn decimate_polyline(
+    coords: &[(f64, f64)],
+    column_width: f64,
+) -> Option<Vec<(f64, f64)>> {
+    let (x_min, x_max) = coords
+        .iter()
+        .fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), &(x, _)| {
+            (min.min(x), max.max(x))
+        });
+    let n_columns = ((x_max - x_min) / column_width).floor() + 1.0;
+    if (coords.len() as f64) < n_columns * MIN_POINTS_PER_COLUMN {
+        return None;
+    }
+
+    let column = |x: f64| (x / column_width).floor();
+
+    let mut result = Vec::new();
+    let mut begin = 0;
+    while begin < coords.len() {
+        let cur_column = column(coords[begin].0);
+
+        let mut end = begin + 1;
+        let mut min = begin;
+        let mut max = begin;
+        while end < coords.len() && column(coords[end].0) == cur_column {
+            if coords[end].1 < coords[min].1 {
+                min = end;
+            }
+            if coords[end].1 > coords[max].1 {
+                max = end;
+            }
+            end += 1;
+        }
+
+        // Keep the order of the points
+        let mut indices = [begin, min, max, end - 1];
+        indices.sort_unstable();
+
+        let mut prev = None;
+        for i in indices {
+            if prev != Some(i) {
+                result.push(coords[i]);
+                prev = Some(i);
+            }
+        }
+
+        begin = end;
+    }
+
+    Some(result)
+}
+
+// The set of the pixels that the center line of the polyline passes through
+#[cfg(test)]
+fn rasterize(coords: &[(f64, f64)]) -> std::collections::HashSet<(i64, i64)> {
+    let mut pixels = std::collections::HashSet::new();
+
+    for w in coords.windows(2) {
+        let ((x0, y0), (x1, y1)) = if w[0].0 <= w[1].0 {
+            (w[0], w[1])
+        } else {
+            (w[1], w[0])
+        };
+
+        let y_at = |x: f64| {
+            if x1 == x0 {
+                y0
+            } else {
+                y0 + (y1 - y0) * (x - x0) / (x1 - x0)
+            }
+        };
+
+        for col in (x0.floor() as i64)..=(x1.floor() as i64) {
+            // The part of the segment in this column
+            let xa = (col as f64).max(x0);
+            let xb = ((col + 1) as f64).min(x1);
+            let (ya, yb) = if x1 == x0 {
+                (y0, y1)
+            } else {
+                (y_at(xa), y_at(xb))
+            };
+
+            for row in (ya.min(yb).floor() as i64)..=(ya.max(yb).floor() as i64) {
+                pixels.insert((col, row));
+            }
+        }
+    }
+
+    pixels
+}
+
+// A deterministic pseudo-random number generator for tests
+#[cfg(test)]
+fn xorshift(state: &mut u64) -> f64 {
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    (*state >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[test]
+fn test_decimate_polyline() {
+    let mut rng = 42_u64;
+
+    // A noisy sine wave
+    let sine: Vec<(f64, f64)> = (0..100000)
+        .map(|i| {
+            let x = i as f64 * 0.005;
+            (
+                x,
+                200.0 + 100.0 * (x / 20.0).sin() + 10.0 * xorshift(&mut rng),
+            )
+        })
+        .collect();
+
+    // A random walk
+    let mut y = 300.0;
+    let random_walk: Vec<(f64, f64)> = (0..100000)
+        .map(|i| {
+            y += xorshift(&mut rng) - 0.5;
+            (10.0 + i as f64 * 0.007, y)
+        })
+        .collect();
+
+    // x is not monotonic
+    let zigzag: Vec<(f64, f64)> = (0..100000)
+        .map(|i| {
+            let x = 100.0 + 50.0 * (i as f64 / 3000.0).sin() + 0.1 * xorshift(&mut rng);
+            (x, 100.0 + i as f64 * 0.003)
+        })
+        .collect();
+
+    for coords in [sine, random_walk, zigzag] {
+        let decimated = decimate_polyline(&coords, 1.0).unwrap();
+        assert!(decimated.len() < coords.len() / 4);
+
+        // The ends are kept
+        assert_eq!(decimated.first(), coords.first());
+        assert_eq!(decimated.last(), coords.last());
+
+        // The center line passes through the same pixels
+        assert_eq!(rasterize(&decimated), rasterize(&coords));
+    }
+
+    // Not dense enough
+    let sparse = [(0.0, 0.0), (10.0, 1.0), (20.0, 0.0)];
+    assert_eq!(decimate_polyline(&sparse, 1.0), None);
+}
+
+// Draws the polylines with the stroke pipeline, with and without decimation,
+// and compares the images against the bounds noted at the top of this file.
+#[test]
+fn test_decimate_polyline_image() {
+    use crate::gpu_test::{create_test_texture, draw_test, read_test_texture, test_gpu_context};
+    use crate::stroke::push_polyline;
+    use lyon::tessellation::LineCap;
+    use wgpu::util::DeviceExt;
+
+    let gpu = match test_gpu_context() {
+        Some(gpu) => gpu,
+        None => return,
+    };
+
+    let black = 0xff000000_u32 as i32;
+    let draw = |coords: &[(f64, f64)], line_width: f32| {
+        let mut points = Vec::new();
+        push_polyline(
+            &mut points,
+            coords.iter().cloned(),
+            line_width,
+            LineCap::Round,
+            black,
+        );
+
+        // The same points as the start and the end of the segments
+        let buffer = |contents: &[u8]| {
+            gpu.device
+                .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                    label: None,
+                    contents,
+                    usage: wgpu::BufferUsages::VERTEX,
+                })
+        };
+        let from_buffer = buffer(bytemuck::cast_slice(&points));
+        let to_buffer = buffer(bytemuck::cast_slice(&points[1..]));
+
+        let texture = create_test_texture(gpu, 1);
+        draw_test(
+            gpu,
+            &texture.create_view(&wgpu::TextureViewDescriptor::default()),
+            1,
+            1,
+            |p| &p.stroke_render_pipeline,
+            &[&from_buffer, &to_buffer],
+            None,
+            6,
+            points.len() as u32 - 1,
+        );
+        read_test_texture(gpu, &texture, 1)
+    };
+
+    // 100 points per column of pixels
+    let mut rng = 42_u64;
+    let xs: Vec<f64> = (0..6000).map(|i| 2.0 + 60.0 * i as f64 / 5999.0).collect();
+    let sine: Vec<(f64, f64)> = xs
+        .iter()
+        .map(|&x| (x, 32.0 + 20.0 * (x * 0.3).sin()))
+        .collect();
+    let noise: Vec<(f64, f64)> = xs
+        .iter()
+        .map(|&x| (x, 32.0 + 40.0 * (xorshift(&mut rng) - 0.5)))
+        .collect();
+    let step: Vec<(f64, f64)> = xs
+        .iter()
+        .map(|&x| {
+            (
+                x,
+                if (x / 8.0) as i64 % 2 == 0 {
+                    16.3
+                } else {
+                    47.7
+                },
+            )
+        })
+        .collect();
+
+    // lwd = 1 and a thicker one
+    for line_width in [0.75, 2.0] {
+        for (name, coords) in [("sine", &sine), ("noise", &noise), ("step", &step)] {
+            let decimated = decimate_polyline(coords, 1.0).unwrap();
+            let original = draw(coords, line_width);
+            let image = draw(&decimated, line_width);
+
+            // The image is white on no ink, so a darker pixel has a smaller value
+            let darker = original
+                .iter()
+                .zip(&image)
+                .map(|(a, b)| a - b)
+                .fold(0.0, f32::max);
+            let mean = original
+                .iter()
+                .zip(&image)
+                .map(|(a, b)| (a - b).abs())
+                .sum::<f32>()
+                / original.len() as f32;
+            assert!(
+                darker < 0.1 && mean < 0.1,
+                "{name} (lwd {line_width}): darker by {darker}, mean difference {mean}"
+            );
+        }
+    }
+}
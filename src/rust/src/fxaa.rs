@@ -0,0 +1,180 @@
+// MSAA is expensive on software adapters like lavapipe. FXAA is a cheaper
+// alternative; the page is rendered without MSAA, and the edges are smoothed
+// by a full-screen pass afterwards. The quality is a bit lower than MSAA,
+// especially on thin lines, but the cost doesn't depend on the number of the
+// shapes.
+
+use crate::gpu_context::GpuContext;
+
+pub(crate) struct Fxaa {
+    // The framebuffer to render the page into
+    pub(crate) view: wgpu::TextureView,
+    bind_group: wgpu::BindGroup,
+}
+
+impl Fxaa {
+    pub(crate) fn new(gpu: &GpuContext, extent: wgpu::Extent3d) -> Self {
+        let device = &gpu.device;
+
+        let view = device
+            .create_texture(&wgpu::TextureDescriptor {
+                label: Some("wgpugd framebuffer for FXAA"),
+                size: extent,
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: wgpu::TextureFormat::Rgba8Unorm,
+                // This is rendered to, and then read by FXAA
+                usage: wgpu::TextureUsages::RENDER_ATTACHMENT
+                    | wgpu::TextureUsages::TEXTURE_BINDING,
+            })
+            .create_view(&wgpu::TextureViewDescriptor::default());
+
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("wgpugd bind group for FXAA"),
+            layout: &gpu.fxaa_bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&gpu.fxaa_sampler),
+                },
+            ],
+        });
+
+        Self { view, bind_group }
+    }
+
+    // Draws the anti-aliased image onto the target.
+    pub(crate) fn apply(
+        &self,
+        gpu: &GpuContext,
+        encoder: &mut wgpu::CommandEncoder,
+        target: &wgpu::TextureView,
+    ) {
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("wgpugd FXAA render pass"),
+            color_attachments: &[wgpu::RenderPassColorAttachment {
+                view: target,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::WHITE),
+                    store: true,
+                },
+            }],
+            depth_stencil_attachment: None,
+        });
+
+        render_pass.set_pipeline(&gpu.fxaa_pipeline);
+        render_pass.set_bind_group(0, &self.bind_group, &[]);
+        // The triangle covering the whole target is generated in the shader
+        render_pass.draw(0..3, 0..1);
+    }
+}
+
+// Draws a black triangle on the white background.
+#[cfg(test)]
+fn draw_test_triangle(
+    gpu: &'static GpuContext,
+    target: &wgpu::TextureView,
+    scale: u32,
+    sample_count: u32,
+) {
+    use wgpu::util::DeviceExt;
+
+    // Edges of different slopes
+    let vertices: Vec<crate::Vertex> = [[4.0, 10.0], [60.0, 26.0], [20.0, 60.0]]
+        .into_iter()
+        .map(|position| crate::Vertex {
+            position,
+            color: 0xff000000,
+            depth: 0.5,
+        })
+        .collect();
+    let vertex_buffer = gpu
+        .device
+        .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: None,
+            contents: bytemuck::cast_slice(&vertices),
+            usage: wgpu::BufferUsages::VERTEX,
+        });
+
+    crate::gpu_test::draw_test(
+        gpu,
+        target,
+        scale,
+        sample_count,
+        |p| &p.render_pipeline,
+        &[&vertex_buffer],
+        None,
+        3,
+        1,
+    );
+}
+
+#[test]
+fn test_fxaa_against_supersampled_reference() {
+    use crate::gpu_test::{create_test_texture, read_test_texture, test_gpu_context, TEST_SIZE};
+
+    let gpu = match test_gpu_context() {
+        Some(gpu) => gpu,
+        None => return,
+    };
+
+    let view =
+        |texture: &wgpu::Texture| texture.create_view(&wgpu::TextureViewDescriptor::default());
+
+    // The reference is the coverage computed from 8x8 samples per pixel
+    let reference_texture = create_test_texture(gpu, 8);
+    draw_test_triangle(gpu, &view(&reference_texture), 8, 1);
+    let reference = read_test_texture(gpu, &reference_texture, 8);
+
+    let aliased_texture = create_test_texture(gpu, 1);
+    draw_test_triangle(gpu, &view(&aliased_texture), 1, 1);
+    let aliased = read_test_texture(gpu, &aliased_texture, 1);
+
+    let msaa_texture = create_test_texture(gpu, 1);
+    draw_test_triangle(gpu, &view(&msaa_texture), 1, 4);
+    let msaa = read_test_texture(gpu, &msaa_texture, 1);
+
+    let extent = wgpu::Extent3d {
+        width: TEST_SIZE,
+        height: TEST_SIZE,
+        depth_or_array_layers: 1,
+    };
+    let fxaa = Fxaa::new(gpu, extent);
+    draw_test_triangle(gpu, &fxaa.view, 1, 1);
+    let fxaa_texture = create_test_texture(gpu, 1);
+    let mut encoder = gpu
+        .device
+        .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+    fxaa.apply(gpu, &mut encoder, &view(&fxaa_texture));
+    gpu.queue.submit(Some(encoder.finish()));
+    let fxaa = read_test_texture(gpu, &fxaa_texture, 1);
+
+    let error = |image: &[f32]| -> f32 {
+        image
+            .iter()
+            .zip(reference.iter())
+            .map(|(a, b)| (a - b).abs())
+            .sum()
+    };
+
+    let error_aliased = error(&aliased);
+    let error_msaa = error(&msaa);
+    let error_fxaa = error(&fxaa);
+
+    assert!(error_msaa < error_aliased);
+    assert!(
+        error_fxaa < error_aliased * 0.9,
+        "FXAA: {error_fxaa}, no anti-aliasing: {error_aliased}"
+    );
+    // Not as good as MSAA, but comparable
+    assert!(
+        error_fxaa < error_msaa * 3.0,
+        "FXAA: {error_fxaa}, MSAA: {error_msaa}"
+    );
+}
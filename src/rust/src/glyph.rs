@@ -0,0 +1,237 @@
+// Since axis labels repeat the same digits thousands of times, it's wasteful to
+// trace and tessellate every glyph of every string. So, we tessellate each glyph
+// only once, keep the mesh in a buffer shared across pages, and draw it as
+// instances with different transforms and colors.
+
+use std::collections::HashMap;
+use std::ops::Range;
+
+use lyon::lyon_tessellation::VertexBuffers;
+use lyon::math::Point;
+use lyon::tessellation::geometry_builder::*;
+use lyon::tessellation::{FillOptions, FillRule, FillTessellator, FillVertex};
+use lyon::tessellation::{StrokeOptions, StrokeTessellator, StrokeVertex};
+
+use crate::batch::BoundingBox;
+use crate::text::STEM_DARKENING;
+use crate::GlyphVertex;
+
+// (key of the face, glyph id, tolerance bucket, stem darkening)
+type GlyphKey = (usize, u16, i32, bool);
+
+#[derive(Debug, Clone)]
+pub(crate) struct GlyphMesh {
+    pub(crate) indices: Range<u32>,
+    // The size of the font (in points) on which the mesh is tessellated
+    pub(crate) size: f32,
+    // The extent of the mesh, in the same coordinates as the vertices
+    pub(crate) bounds: BoundingBox,
+}
+
+// A run of the instances of the same glyph
+#[derive(Debug, Clone)]
+pub(crate) struct GlyphRun {
+    pub(crate) indices: Range<u32>,
+    pub(crate) instances: Range<u32>,
+}
+
+pub(crate) struct GlyphMeshCache {
+    meshes: HashMap<GlyphKey, Option<GlyphMesh>>,
+    pub(crate) geometry: VertexBuffers<GlyphVertex, u32>,
+    // The tessellation tolerance in points. As the meshes are drawn at most as
+    // large as the size on which they are tessellated, this is the upper
+    // bound of the error on the page.
+    tolerance: f32,
+}
+
+impl GlyphMeshCache {
+    pub(crate) fn new(tolerance: f32) -> Self {
+        Self {
+            meshes: HashMap::new(),
+            geometry: VertexBuffers::new(),
+            tolerance,
+        }
+    }
+
+    // The tessellation error is proportional to the size of the font. To keep
+    // the error within the tolerance regardless of the size, bucket the sizes
+    // by the powers of 2, and tessellate the glyph on the largest size of the
+    // bucket.
+    pub(crate) fn tolerance_bucket(size: f32) -> i32 {
+        size.max(1.0).log2().ceil() as _
+    }
+
+    // Returns `None` if the glyph has no outline (e.g. a space). If `darken` is
+    // true, the stems are thickened by stroking the outline.
+    pub(crate) fn get_or_insert(
+        &mut self,
+        face_key: usize,
+        font: &ttf_parser::Face,
+        glyph_id: ttf_parser::GlyphId,
+        size: f32,
+        darken: bool,
+    ) -> Option<GlyphMesh> {
+        let bucket = Self::tolerance_bucket(size);
+        let geometry = &mut self.geometry;
+        let tolerance = self.tolerance;
+
+        self.meshes
+            .entry((face_key, glyph_id.0, bucket, darken))
+            .or_insert_with(|| {
+                let mesh_size = (bucket as f32).exp2();
+                let scale = mesh_size / font.height() as f32;
+
+                let mut builder = crate::text::LyonOutlineBuilder::new(scale);
+                font.outline_glyph(glyph_id, &mut builder)?;
+                let path = builder.build();
+
+                let begin = geometry.indices.len() as u32;
+                let begin_vertex = geometry.vertices.len();
+
+                // The stroke that thickens the stems overlaps the fill, and
+                // the overlap would be blended twice with a translucent color.
+                // So, the union of the both is filled instead.
+                let (path, fill_options) = if darken {
+                    let fill_options =
+                        FillOptions::tolerance(tolerance).with_fill_rule(FillRule::NonZero);
+                    (darkened_outline(&path, tolerance)?, fill_options)
+                } else {
+                    (path, FillOptions::tolerance(tolerance))
+                };
+
+                let mut fill_tess = FillTessellator::new();
+                fill_tess
+                    .tessellate_path(
+                        &path,
+                        &fill_options,
+                        &mut BuffersBuilder::new(geometry, |vertex: FillVertex| GlyphVertex {
+                            position: vertex.position().to_array(),
+                        }),
+                    )
+                    .ok()?;
+
+                let end = geometry.indices.len() as u32;
+
+                let bounds = BoundingBox::from_points(
+                    geometry.vertices[begin_vertex..]
+                        .iter()
+                        .map(|v| (v.position[0], v.position[1])),
+                );
+
+                Some(GlyphMesh {
+                    indices: begin..end,
+                    size: mesh_size,
+                    bounds,
+                })
+            })
+            .clone()
+    }
+}
+
+// Returns the outline thickened by `STEM_DARKENING`, as the triangles of the
+// fill and the stroke of the outline. They are all turned in the same direction
+// so that filling them with the non-zero rule gives their union.
+fn darkened_outline(path: &lyon::path::Path, tolerance: f32) -> Option<lyon::path::Path> {
+    let mut triangles: VertexBuffers<Point, u32> = VertexBuffers::new();
+
+    FillTessellator::new()
+        .tessellate_path(
+            path,
+            &FillOptions::tolerance(tolerance),
+            &mut BuffersBuilder::new(&mut triangles, |vertex: FillVertex| vertex.position()),
+        )
+        .ok()?;
+
+    // The mesh is shared within the bucket, so assume the typical size in the
+    // bucket, 3/4 of the mesh size.
+    let stroke_width = STEM_DARKENING / 0.75;
+    StrokeTessellator::new()
+        .tessellate_path(
+            path,
+            &StrokeOptions::tolerance(tolerance).with_line_width(stroke_width),
+            &mut BuffersBuilder::new(&mut triangles, |vertex: StrokeVertex| vertex.position()),
+        )
+        .ok()?;
+
+    let mut builder = lyon::path::Path::builder();
+    for triangle in triangles.indices.chunks_exact(3) {
+        let [a, b, c] = [0, 1, 2].map(|i| triangles.vertices[triangle[i] as usize]);
+        let area = (b - a).cross(c - a);
+        if area == 0.0 {
+            continue;
+        }
+
+        let (b, c) = if area > 0.0 { (b, c) } else { (c, b) };
+        builder.begin(a);
+        builder.line_to(b);
+        builder.line_to(c);
+        builder.close();
+    }
+
+    Some(builder.build())
+}
+
+// The total area of the triangles
+#[cfg(test)]
+fn triangles_area(vertices: &[[f32; 2]], indices: &[u32]) -> f32 {
+    indices
+        .chunks_exact(3)
+        .map(|triangle| {
+            let [a, b, c] = [0, 1, 2].map(|i| {
+                let [x, y] = vertices[triangle[i] as usize];
+                lyon::math::point(x, y)
+            });
+            (b - a).cross(c - a).abs() / 2.0
+        })
+        .sum()
+}
+
+#[test]
+fn test_darkened_glyph() {
+    let font_data = match crate::text::test_font_data() {
+        Some(font_data) => font_data,
+        None => return,
+    };
+    let font = ttf_parser::Face::from_slice(&font_data, 0).unwrap();
+    let glyph_id = font.glyph_index('o').unwrap();
+
+    // 8pt is in the bucket of the 8pt meshes
+    let size = 8.0;
+    let mut cache = GlyphMeshCache::new(0.01);
+    let plain = cache
+        .get_or_insert(0, &font, glyph_id, size, false)
+        .unwrap();
+    let darkened = cache.get_or_insert(0, &font, glyph_id, size, true).unwrap();
+
+    let vertices: Vec<[f32; 2]> = cache.geometry.vertices.iter().map(|v| v.position).collect();
+    let area = |indices: Range<u32>| {
+        triangles_area(
+            &vertices,
+            &cache.geometry.indices[indices.start as usize..indices.end as usize],
+        )
+    };
+    let plain_area = area(plain.indices);
+    let darkened_area = area(darkened.indices);
+
+    let mut builder = crate::text::LyonOutlineBuilder::new(size / font.height() as f32);
+    font.outline_glyph(glyph_id, &mut builder).unwrap();
+    let mut stroke: VertexBuffers<[f32; 2], u32> = VertexBuffers::new();
+    StrokeTessellator::new()
+        .tessellate_path(
+            &builder.build(),
+            &StrokeOptions::tolerance(0.01).with_line_width(STEM_DARKENING / 0.75),
+            &mut BuffersBuilder::new(&mut stroke, |vertex: StrokeVertex| {
+                vertex.position().to_array()
+            }),
+        )
+        .unwrap();
+    let stroke_area = triangles_area(&stroke.vertices, &stroke.indices);
+
+    // Only the outer half of the stroke adds to the fill. If they overlapped,
+    // the inner half would be counted twice.
+    let added = darkened_area - plain_area;
+    assert!(
+        (added - stroke_area / 2.0).abs() < stroke_area * 0.1,
+        "added {added} by the stroke of {stroke_area}"
+    );
+}
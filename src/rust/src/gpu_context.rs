@@ -0,0 +1,446 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use once_cell::sync::OnceCell;
+use wgpu::util::DeviceExt;
+
+use crate::clip::ClipRect;
+use crate::render_pipeline::{create_render_pipeline, depth_stencil_state, DEPTH_FORMAT};
+use crate::shader_cache::{cache_dir, ShaderCache};
+use crate::stroke::StrokePoint;
+use crate::{
+    AtlasGlyphInstance, GlyphInstance, GlyphVertex, SDFInstance, SDFVertex, Vertex, RECT_INDICES,
+    RECT_VERTICES,
+};
+
+// Creating an instance, an adapter, and a device, and compiling the shaders
+// take far more time than rendering a typical plot. So, they are created only
+// once per process and shared by all the devices. Each device only allocates
+// its own textures and buffers.
+static GPU_CONTEXT: OnceCell<GpuContext> = OnceCell::new();
+
+pub(crate) struct GpuContext {
+    pub(crate) device: wgpu::Device,
+    pub(crate) queue: wgpu::Queue,
+
+    pub(crate) globals_bind_group_layout: wgpu::BindGroupLayout,
+    pub(crate) atlas_bind_group_layout: wgpu::BindGroupLayout,
+    pub(crate) atlas_sampler: wgpu::Sampler,
+
+    // The sample counts of MSAA that both the framebuffer and the depth buffer
+    // support
+    pub(crate) sample_counts: Vec<u32>,
+
+    shaders: ShaderModules,
+
+    // The pipelines depend on the sample count of MSAA, which can differ
+    // between devices. They are created on demand, and live as long as the
+    // context does.
+    render_pipelines: Mutex<HashMap<u32, &'static RenderPipelines>>,
+
+    pub(crate) downsample_bind_group_layout: wgpu::BindGroupLayout,
+    pub(crate) downsample_pipeline: wgpu::RenderPipeline,
+
+    pub(crate) fxaa_bind_group_layout: wgpu::BindGroupLayout,
+    pub(crate) fxaa_sampler: wgpu::Sampler,
+    pub(crate) fxaa_pipeline: wgpu::RenderPipeline,
+
+    // The quad for SDF shapes never changes, so this can be shared as well.
+    pub(crate) sdf_vertex_buffer: wgpu::Buffer,
+    pub(crate) sdf_index_buffer: wgpu::Buffer,
+}
+
+struct ShaderModules {
+    polygon: wgpu::ShaderModule,
+    sdf_shape: wgpu::ShaderModule,
+    stroke: wgpu::ShaderModule,
+    glyph: wgpu::ShaderModule,
+    atlas_text: wgpu::ShaderModule,
+}
+
+pub(crate) struct RenderPipelines {
+    pub(crate) render_pipeline: wgpu::RenderPipeline,
+    pub(crate) sdf_render_pipeline: wgpu::RenderPipeline,
+    pub(crate) stroke_render_pipeline: wgpu::RenderPipeline,
+    pub(crate) glyph_render_pipeline: wgpu::RenderPipeline,
+    pub(crate) atlas_render_pipeline: wgpu::RenderPipeline,
+}
+
+// The shaders of the shapes share the clip rect (c.f. clip.rs)
+fn with_clip(source: &str) -> String {
+    format!("{}\n{source}", include_str!("shaders/clip.wgsl"))
+}
+
+fn sdf_shape_source() -> String {
+    let source = with_clip(include_str!("shaders/sdf_shape.wgsl"));
+    if cfg!(feature = "bench-full-page-sdf") {
+        source.replace("let FULL_PAGE = false;", "let FULL_PAGE = true;")
+    } else {
+        source
+    }
+}
+
+// Returns the context, creating it on the first call.
+pub(crate) fn gpu_context() -> extendr_api::Result<&'static GpuContext> {
+    gpu_context_with_cache_dir(cache_dir)
+}
+
+// `cache_dir` is called only when the context is created. The tests, which run
+// without R, use this instead of `gpu_context()`.
+pub(crate) fn gpu_context_with_cache_dir(
+    cache_dir: impl FnOnce() -> Option<PathBuf>,
+) -> extendr_api::Result<&'static GpuContext> {
+    GPU_CONTEXT.get_or_try_init(|| pollster::block_on(GpuContext::new(cache_dir())))
+}
+
+impl GpuContext {
+    pub(crate) async fn new(cache_dir: Option<PathBuf>) -> extendr_api::Result<Self> {
+        // Set envvar WGPU_BACKEND to specific backend (e.g., vulkan, dx12, metal, opengl)
+        let backend = wgpu::util::backend_bits_from_env().unwrap_or_else(wgpu::Backends::all);
+
+        // An `Instance` is a "context for all other wgpu objects"
+        let instance = wgpu::Instance::new(backend);
+
+        // An `Adapter` is a "handle to a physical graphics"
+        let adapter = instance
+            .request_adapter(&wgpu::RequestAdapterOptions {
+                power_preference: wgpu::PowerPreference::default(),
+                compatible_surface: None, // Currently no window so no surface
+                force_fallback_adapter: false,
+            })
+            .await
+            .ok_or_else(|| extendr_api::Error::Other("No GPU adapter is found".to_string()))?;
+
+        // SPIR-V passthrough is needed for the shader cache. This is available
+        // only on Vulkan. The adapter specific format features allow the
+        // sample counts of MSAA other than 1 and 4.
+        let features = adapter.features()
+            & (wgpu::Features::SPIRV_SHADER_PASSTHROUGH
+                | wgpu::Features::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES);
+
+        // A `Device` is a "connection to a graphics device" and a `Queue` is a command queue.
+        let (device, queue) = adapter
+            .request_device(
+                &wgpu::DeviceDescriptor {
+                    label: Some("wgpugd device"),
+                    features,
+                    limits: Default::default(),
+                },
+                None,
+            )
+            .await
+            .map_err(|e| extendr_api::Error::Other(format!("Failed to get a GPU device: {e}")))?;
+
+        let shader_cache = ShaderCache::new(&adapter.get_info(), device.features(), cache_dir);
+
+        // Without the adapter specific format features, only the ones
+        // guaranteed by WebGPU are available.
+        let format_flags = |format: wgpu::TextureFormat| {
+            if device
+                .features()
+                .contains(wgpu::Features::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES)
+            {
+                adapter.get_texture_format_features(format).flags
+            } else {
+                format.describe().guaranteed_format_features.flags
+            }
+        };
+        let color_flags = format_flags(wgpu::TextureFormat::Rgba8Unorm);
+        let depth_flags = format_flags(DEPTH_FORMAT);
+        let sample_counts = [1, 2, 4, 8]
+            .into_iter()
+            .filter(|&count| {
+                color_flags.sample_count_supported(count)
+                    && depth_flags.sample_count_supported(count)
+            })
+            .collect();
+
+        let globals_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("wgpugd globals bind group layout"),
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                    // The clip rect, selected by the dynamic offset (c.f. clip.rs)
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: true,
+                            min_binding_size: wgpu::BufferSize::new(
+                                std::mem::size_of::<ClipRect>() as _,
+                            ),
+                        },
+                        count: None,
+                    },
+                ],
+            });
+
+        let atlas_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("wgpugd bind group layout for the SDF atlas"),
+                entries: &[
+                    // The vertex shader also needs the size of the texture
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                            multisampled: false,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                        count: None,
+                    },
+                ],
+            });
+
+        let atlas_sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("wgpugd sampler for the SDF atlas"),
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+
+        let shaders = ShaderModules {
+            polygon: shader_cache.create_shader_module(
+                &device,
+                "shader",
+                &with_clip(include_str!("shaders/shader.wgsl")),
+            ),
+            sdf_shape: shader_cache.create_shader_module(&device, "sdf_shape", &sdf_shape_source()),
+            stroke: shader_cache.create_shader_module(
+                &device,
+                "stroke",
+                &with_clip(include_str!("shaders/stroke.wgsl")),
+            ),
+            glyph: shader_cache.create_shader_module(
+                &device,
+                "glyph",
+                &with_clip(include_str!("shaders/glyph.wgsl")),
+            ),
+            atlas_text: shader_cache.create_shader_module(
+                &device,
+                "atlas_text",
+                &with_clip(include_str!("shaders/atlas_text.wgsl")),
+            ),
+        };
+
+        let downsample_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("wgpugd bind group layout for downsampling"),
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                            multisampled: false,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                ],
+            });
+
+        // The output of the downsampling is never multisampled.
+        let downsample_pipeline = create_render_pipeline(
+            &device,
+            "wgpugd render pipeline layout for downsampling",
+            "wgpugd render pipeline for downsampling",
+            &[&downsample_bind_group_layout],
+            &shader_cache.create_shader_module(
+                &device,
+                "downsample",
+                include_str!("shaders/downsample.wgsl"),
+            ),
+            &[],
+            1,
+            None,
+        );
+
+        let fxaa_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("wgpugd bind group layout for FXAA"),
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                            multisampled: false,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                        count: None,
+                    },
+                ],
+            });
+
+        // FXAA samples between the texels, and the edges of the image are
+        // extended.
+        let fxaa_sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("wgpugd sampler for FXAA"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+
+        let fxaa_pipeline = create_render_pipeline(
+            &device,
+            "wgpugd render pipeline layout for FXAA",
+            "wgpugd render pipeline for FXAA",
+            &[&fxaa_bind_group_layout],
+            &shader_cache.create_shader_module(&device, "fxaa", include_str!("shaders/fxaa.wgsl")),
+            &[],
+            1,
+            None,
+        );
+
+        let sdf_vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("wgpugd vertex buffer"),
+            contents: bytemuck::cast_slice(RECT_VERTICES),
+            usage: wgpu::BufferUsages::VERTEX,
+        });
+
+        let sdf_index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("wgpugd index buffer"),
+            contents: bytemuck::cast_slice(RECT_INDICES),
+            usage: wgpu::BufferUsages::INDEX,
+        });
+
+        Ok(Self {
+            device,
+            queue,
+            sample_counts,
+
+            globals_bind_group_layout,
+            atlas_bind_group_layout,
+            atlas_sampler,
+
+            shaders,
+            render_pipelines: Mutex::new(HashMap::new()),
+
+            downsample_bind_group_layout,
+            downsample_pipeline,
+
+            fxaa_bind_group_layout,
+            fxaa_sampler,
+            fxaa_pipeline,
+
+            sdf_vertex_buffer,
+            sdf_index_buffer,
+        })
+    }
+    // Returns the pipelines for the sample count, creating them on the first
+    // call.
+    pub(crate) fn render_pipelines(&'static self, sample_count: u32) -> &'static RenderPipelines {
+        let mut pipelines = self.render_pipelines.lock().unwrap();
+        pipelines.entry(sample_count).or_insert_with(|| {
+            // The context is never dropped, so leaking is fine.
+            Box::leak(Box::new(self.create_render_pipelines(sample_count)))
+        })
+    }
+
+    fn create_render_pipelines(&self, sample_count: u32) -> RenderPipelines {
+        let device = &self.device;
+
+        let render_pipeline = create_render_pipeline(
+            device,
+            "wgpugd render pipeline layout",
+            "wgpugd render pipeline",
+            &[&self.globals_bind_group_layout],
+            &self.shaders.polygon,
+            &[Vertex::desc()],
+            sample_count,
+            depth_stencil_state(true),
+        );
+
+        let sdf_render_pipeline = create_render_pipeline(
+            device,
+            "wgpugd render pipeline layout for SDF shapes",
+            "wgpugd render pipeline for SDF shapes",
+            &[&self.globals_bind_group_layout],
+            &self.shaders.sdf_shape,
+            &[SDFVertex::desc(), SDFInstance::desc()],
+            // Technically, this doesn't need to be multisampled, as the SDF
+            // shapes are out of scope of MSAA anyway, but as we share the
+            // one renderpipline, the sample count must match the others.
+            sample_count,
+            depth_stencil_state(false),
+        );
+
+        let stroke_render_pipeline = create_render_pipeline(
+            device,
+            "wgpugd render pipeline layout for lines",
+            "wgpugd render pipeline for lines",
+            &[&self.globals_bind_group_layout],
+            &self.shaders.stroke,
+            &[StrokePoint::desc_from(), StrokePoint::desc_to()],
+            sample_count,
+            depth_stencil_state(false),
+        );
+
+        let glyph_render_pipeline = create_render_pipeline(
+            device,
+            "wgpugd render pipeline layout for glyphs",
+            "wgpugd render pipeline for glyphs",
+            &[&self.globals_bind_group_layout],
+            &self.shaders.glyph,
+            &[GlyphVertex::desc(), GlyphInstance::desc()],
+            sample_count,
+            depth_stencil_state(false),
+        );
+
+        let atlas_render_pipeline = create_render_pipeline(
+            device,
+            "wgpugd render pipeline layout for the SDF atlas",
+            "wgpugd render pipeline for the SDF atlas",
+            &[&self.globals_bind_group_layout, &self.atlas_bind_group_layout],
+            &self.shaders.atlas_text,
+            &[AtlasGlyphInstance::desc()],
+            sample_count,
+            depth_stencil_state(false),
+        );
+
+        RenderPipelines {
+            render_pipeline,
+            sdf_render_pipeline,
+            stroke_render_pipeline,
+            glyph_render_pipeline,
+            atlas_render_pipeline,
+        }
+    }
+}
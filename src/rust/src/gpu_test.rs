@@ -0,0 +1,347 @@
+// Helpers for the tests that render on the GPU. They need an adapter (e.g.
+// lavapipe on CI), and are skipped if there's none.
+
+use wgpu::util::DeviceExt;
+
+use crate::clip::ClipRect;
+use crate::gpu_context::{gpu_context_with_cache_dir, GpuContext, RenderPipelines};
+use crate::marker::SdfShape;
+use crate::render_pipeline::DEPTH_FORMAT;
+use crate::{create_globals_bind_group, Globals, SDFInstance, RECT_INDICES};
+
+// The width and the height of the test images, in points
+pub(crate) const TEST_SIZE: u32 = 64;
+
+// The shared context without the shader cache
+pub(crate) fn test_gpu_context() -> Option<&'static GpuContext> {
+    match gpu_context_with_cache_dir(|| None) {
+        Ok(gpu) => Some(gpu),
+        Err(e) => {
+            eprintln!("Skipped the test: {e:?}");
+            None
+        }
+    }
+}
+
+// Creates a texture of `TEST_SIZE` times `scale` pixels that can be read back.
+pub(crate) fn create_test_texture(gpu: &GpuContext, scale: u32) -> wgpu::Texture {
+    gpu.device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("wgpugd test texture"),
+        size: wgpu::Extent3d {
+            width: TEST_SIZE * scale,
+            height: TEST_SIZE * scale,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: wgpu::TextureFormat::Rgba8Unorm,
+        usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+    })
+}
+
+// Draws on the white background. If `index_buffer` is given, `count` is the
+// number of the indices, otherwise the number of the vertices.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn draw_test(
+    gpu: &'static GpuContext,
+    target: &wgpu::TextureView,
+    scale: u32,
+    sample_count: u32,
+    pipeline: fn(&RenderPipelines) -> &wgpu::RenderPipeline,
+    vertex_buffers: &[&wgpu::Buffer],
+    index_buffer: Option<&wgpu::Buffer>,
+    count: u32,
+    instances: u32,
+) {
+    let device = &gpu.device;
+
+    let globals_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: None,
+        contents: bytemuck::cast_slice(&[Globals {
+            resolution: [TEST_SIZE as _, TEST_SIZE as _],
+            scale: scale as _,
+            _padding: 0.0,
+        }]),
+        usage: wgpu::BufferUsages::UNIFORM,
+    });
+    let clip_rect_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: None,
+        contents: bytemuck::cast_slice(&[ClipRect::UNBOUNDED]),
+        usage: wgpu::BufferUsages::UNIFORM,
+    });
+    let globals_bind_group = create_globals_bind_group(
+        device,
+        &gpu.globals_bind_group_layout,
+        &globals_buffer,
+        &clip_rect_buffer,
+    );
+
+    let multisampled_framebuffer = (sample_count > 1).then(|| {
+        device
+            .create_texture(&wgpu::TextureDescriptor {
+                label: None,
+                size: wgpu::Extent3d {
+                    width: TEST_SIZE * scale,
+                    height: TEST_SIZE * scale,
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count,
+                dimension: wgpu::TextureDimension::D2,
+                format: wgpu::TextureFormat::Rgba8Unorm,
+                usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+            })
+            .create_view(&wgpu::TextureViewDescriptor::default())
+    });
+    let depth_buffer = device
+        .create_texture(&wgpu::TextureDescriptor {
+            label: None,
+            size: wgpu::Extent3d {
+                width: TEST_SIZE * scale,
+                height: TEST_SIZE * scale,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count,
+            dimension: wgpu::TextureDimension::D2,
+            format: DEPTH_FORMAT,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+        })
+        .create_view(&wgpu::TextureViewDescriptor::default());
+
+    let (view, resolve_target) = match multisampled_framebuffer {
+        Some(ref multisampled_framebuffer) => (multisampled_framebuffer, Some(target)),
+        None => (target, None),
+    };
+
+    let mut encoder =
+        device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+    {
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: None,
+            color_attachments: &[wgpu::RenderPassColorAttachment {
+                view,
+                resolve_target,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::WHITE),
+                    store: true,
+                },
+            }],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: &depth_buffer,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(0.0),
+                    store: false,
+                }),
+                stencil_ops: None,
+            }),
+        });
+
+        render_pass.set_pipeline(pipeline(gpu.render_pipelines(sample_count)));
+        render_pass.set_bind_group(0, &globals_bind_group, &[0]);
+        for (i, buffer) in vertex_buffers.iter().enumerate() {
+            render_pass.set_vertex_buffer(i as _, buffer.slice(..));
+        }
+        match index_buffer {
+            Some(index_buffer) => {
+                render_pass.set_index_buffer(index_buffer.slice(..), wgpu::IndexFormat::Uint16);
+                render_pass.draw_indexed(0..count, 0, 0..instances);
+            }
+            None => render_pass.draw(0..count, 0..instances),
+        }
+    }
+    gpu.queue.submit(Some(encoder.finish()));
+}
+
+// Reads the red channel of the texture into [0, 1], downsampled by `scale`.
+pub(crate) fn read_test_texture(gpu: &GpuContext, texture: &wgpu::Texture, scale: u32) -> Vec<f32> {
+    let device = &gpu.device;
+    let size = TEST_SIZE * scale;
+    // A multiple of `wgpu::COPY_BYTES_PER_ROW_ALIGNMENT`, so no padding
+    let bytes_per_row = size * 4;
+
+    let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+        label: None,
+        size: (bytes_per_row * size) as u64,
+        usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    });
+
+    let mut encoder =
+        device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+    encoder.copy_texture_to_buffer(
+        texture.as_image_copy(),
+        wgpu::ImageCopyBuffer {
+            buffer: &buffer,
+            layout: wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: std::num::NonZeroU32::new(bytes_per_row),
+                rows_per_image: None,
+            },
+        },
+        wgpu::Extent3d {
+            width: size,
+            height: size,
+            depth_or_array_layers: 1,
+        },
+    );
+    gpu.queue.submit(Some(encoder.finish()));
+
+    let buffer_slice = buffer.slice(..);
+    let buffer_future = buffer_slice.map_async(wgpu::MapMode::Read);
+    device.poll(wgpu::Maintain::Wait);
+    pollster::block_on(buffer_future).unwrap();
+
+    let data = buffer_slice.get_mapped_range();
+    let mut result = vec![0.0; (TEST_SIZE * TEST_SIZE) as usize];
+    for y in 0..size {
+        for x in 0..size {
+            let i = ((y / scale) * TEST_SIZE + x / scale) as usize;
+            result[i] += data[((y * size + x) * 4) as usize] as f32 / 255.0;
+        }
+    }
+    result.iter().map(|v| v / (scale * scale) as f32).collect()
+}
+
+// Draws the SDF shapes and returns the image.
+pub(crate) fn draw_test_sdf(gpu: &'static GpuContext, instances: &[SDFInstance]) -> Vec<f32> {
+    let instance_buffer = gpu
+        .device
+        .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: None,
+            contents: bytemuck::cast_slice(instances),
+            usage: wgpu::BufferUsages::VERTEX,
+        });
+
+    let texture = create_test_texture(gpu, 1);
+    draw_test(
+        gpu,
+        &texture.create_view(&wgpu::TextureViewDescriptor::default()),
+        1,
+        // Test the anti-aliasing of the shader itself
+        1,
+        |p| &p.sdf_render_pipeline,
+        &[&gpu.sdf_vertex_buffer, &instance_buffer],
+        Some(&gpu.sdf_index_buffer),
+        RECT_INDICES.len() as _,
+        instances.len() as _,
+    );
+    read_test_texture(gpu, &texture, 1)
+}
+
+#[test]
+fn test_sdf_circle_area() {
+    let gpu = match test_gpu_context() {
+        Some(gpu) => gpu,
+        None => return,
+    };
+
+    // pch = 16 (a filled circle with the border of lwd = 1) at cex 0.1 to 10.
+    // The radius is 0.375 times the font size of 12pt.
+    let black = 0xff000000_u32 as i32;
+    let line_width = 0.75;
+    for cex in [0.1, 0.2, 0.5, 1.0, 2.0, 5.0, 10.0] {
+        let r = 0.375 * 12.0 * cex;
+        let center = (TEST_SIZE as f64 / 2.0 + 0.3, TEST_SIZE as f64 / 2.0 + 0.1);
+        let circle = SDFInstance::new(SdfShape::Circle, center, (r, r), line_width, black, black);
+
+        let image = draw_test_sdf(gpu, &[circle]);
+        let area: f32 = image.iter().map(|v| 1.0 - v).sum();
+
+        let r_outer = r as f32 + line_width * 0.5;
+        let expected = std::f32::consts::PI * r_outer * r_outer;
+        assert!(
+            (area - expected).abs() < expected * 0.05 + 0.1,
+            "cex = {cex}: expected {expected}, got {area}"
+        );
+    }
+
+    // The circles smaller than a pixel (e.g. pch = 16 at cex 0.02, or lwd = 0)
+    // are faded by the area instead of vanishing.
+    let center = (TEST_SIZE as f64 / 2.0 + 0.3, TEST_SIZE as f64 / 2.0 + 0.1);
+    let draw_area = |r: f64, line_width: f32| {
+        let circle = SDFInstance::new(SdfShape::Circle, center, (r, r), line_width, black, black);
+        let image = draw_test_sdf(gpu, &[circle]);
+        image.iter().map(|v| 1.0 - v).sum::<f32>()
+    };
+    for (r, line_width) in [
+        (0.09, 0.75),
+        (0.1, 0.0),
+        (0.2, 0.0),
+        (0.3, 0.0),
+        (0.45, 0.0),
+    ] {
+        let area = draw_area(r, line_width);
+        let r_outer = r as f32 + line_width * 0.5;
+        let expected = std::f32::consts::PI * r_outer * r_outer;
+        assert!(
+            (area - expected).abs() < expected * 0.1 + 0.01,
+            "r = {r}, lwd = {line_width}: expected {expected}, got {area}"
+        );
+    }
+
+    // ...and the area grows smoothly across the threshold of 0.5px
+    let step = 0.02;
+    let mut prev_area = draw_area(0.3, 0.0);
+    for i in 1..=20 {
+        let r = 0.3 + step * i as f64;
+        let area = draw_area(r, 0.0);
+        // Twice the growth of the exact area, plus the rounding to 8 bits
+        let max_growth = 2.0 * std::f32::consts::TAU * r as f32 * step as f32 + 0.02;
+        assert!(
+            area > prev_area - 0.01 && area - prev_area < max_growth,
+            "r = {r}: the area jumps from {prev_area} to {area}"
+        );
+        prev_area = area;
+    }
+
+    // The same semi-transparent color for the fill and the stroke; the part
+    // where the stroke is over the fill is darker, but there should be no
+    // darker ring between the fill and the stroke.
+    let gray = 0x80000000_u32 as i32;
+    let center = (TEST_SIZE as f64 / 2.0, TEST_SIZE as f64 / 2.0);
+    let circle = SDFInstance::new(SdfShape::Circle, center, (20.0, 20.0), 4.0, gray, gray);
+    let image = draw_test_sdf(gpu, &[circle]);
+    // The darkest is the stroke over the fill, i.e. 1 - 0.5 * 0.5
+    let darkest = image.iter().cloned().fold(1.0, f32::min);
+    assert!(darkest > 0.25 - 0.02, "the darkest is {darkest}");
+}
+
+#[test]
+fn test_sdf_cross_area() {
+    let gpu = match test_gpu_context() {
+        Some(gpu) => gpu,
+        None => return,
+    };
+
+    // pch = 4 at cex 2, i.e. two diagonal lines of lwd = 2
+    let black = 0xff000000_u32 as i32;
+    let center = (TEST_SIZE as f64 / 2.0, TEST_SIZE as f64 / 2.0);
+    let (half_length, half_width) = (12.0, 1.0);
+
+    let diagonal = SDFInstance::new(
+        SdfShape::Diagonal,
+        center,
+        (half_length, half_width),
+        0.0,
+        black,
+        black,
+    );
+    let mut cross = diagonal;
+    cross.shape = SdfShape::Cross as _;
+    cross.half_size = [half_length as _, half_length as _];
+    cross.arm_width = half_width as _;
+
+    let line_area = (4.0 * half_length * half_width) as f32;
+    let overlap = (4.0 * half_width * half_width) as f32;
+    for (instance, expected) in [(diagonal, line_area), (cross, 2.0 * line_area - overlap)] {
+        let image = draw_test_sdf(gpu, &[instance]);
+        let area: f32 = image.iter().map(|v| 1.0 - v).sum();
+        assert!(
+            (area - expected).abs() < expected * 0.05,
+            "shape {}: expected {expected}, got {area}",
+            instance.shape
+        );
+    }
+}
@@ -6,83 +6,127 @@ use extendr_api::{
 };
 
 use lyon::path::Path;
-use lyon::tessellation::geometry_builder::*;
-use lyon::tessellation::{FillOptions, FillTessellator, FillVertex};
-use lyon::tessellation::{StrokeOptions, StrokeTessellator, StrokeVertex};
-use ttf_parser::GlyphId;
+use lyon::tessellation::{FillOptions, StrokeOptions};
 
 use glam::f32::Affine2;
 
-use crate::text::FONTDB;
+use crate::batch::{find_batch, push_command, BoundingBox};
+use crate::clip::{clip_region, ClipRect};
+use crate::decimate::decimate_polyline;
+use crate::glyph::{GlyphMesh, GlyphRun};
+use crate::marker::{detect_diamond, detect_triangle, SdfShape};
+use crate::pixel_snap::{hairline, snap_span, snap_stroke_center};
+use crate::sdf_atlas::{distance_bias, AtlasEntry, SDF_GLYPH_SIZE};
+use crate::stats;
+use crate::stroke::push_polyline;
+use crate::tessellate::{TessellationJob, TessellationShape, TessellationStyle};
+use crate::text::{
+    layout_glyphs, parse_face, snap_glyph_origin, x_height_scale, TextMode, FONTDB, STEM_DARKENING,
+    STEM_DARKENING_MAX_SIZE,
+};
+
+// The tolerance is at least this fraction of the size of the shape, so that a
+// huge shape (e.g. a circle mostly outside of the page) doesn't get too many
+// vertices.
+const MIN_RELATIVE_TOLERANCE: f32 = 1e-4;
+
+// Only the rects up to this size in points (e.g. square markers) are drawn as
+// SDF shapes. The larger ones (e.g. the backgrounds of the panels) are not many,
+// and are tessellated with the exact joins.
+const MAX_SDF_RECT_SIZE: f64 = 72.0;
 
-// TODO: determine tolerance nicely
-pub(crate) const DEFAULT_TOLERANCE: f32 = lyon::tessellation::FillOptions::DEFAULT_TOLERANCE;
+// A glyph of a text, placed and ready to draw
+enum PlacedGlyph {
+    Atlas(AtlasEntry, Affine2),
+    Mesh(GlyphMesh, Affine2),
+}
 
 #[derive(Debug, Clone)]
 pub struct DrawCommand {
     pub count: u32,
+    // The extent of the primitives, to tell if the primitives drawn later can
+    // be merged into this command (c.f. `batch.rs`).
+    pub(crate) bbox: BoundingBox,
 }
 
 impl DrawCommand {
-    fn extend(&mut self, count: u32) {
-        self.count += count;
+    pub(crate) fn new(count: u32, bbox: BoundingBox) -> Self {
+        Self { count, bbox }
+    }
+
+    pub(crate) fn merge(&mut self, other: &DrawCommand) {
+        self.count += other.count;
+        self.bbox = self.bbox.union(other.bbox);
     }
 }
 
 #[derive(Debug, Clone)]
 pub enum WgpugdCommand {
-    // Draw tessellated polygons.
+    // Draw tessellated polygons. The count is of tessellation jobs.
     DrawPolygon(DrawCommand),
     // Draw shapes represented by an SDF.
     DrawSDF(DrawCommand),
-    // Set clipping range.
+    // Draw lines as segments. The count is of points.
+    DrawStroke(DrawCommand),
+    // Draw instances of the cached glyph meshes. The count is of glyph runs.
+    DrawGlyph(DrawCommand),
+    // Draw glyphs from the SDF atlas.
+    DrawAtlasGlyph(DrawCommand),
+    // Set clipping range. The scissor is in the pixels of the framebuffer, and
+    // the clip rect is for the bounds that don't fall on the pixel boundaries
+    // (c.f. clip.rs).
     SetClipping {
         x: u32,
         y: u32,
         width: u32,
         height: u32,
+        clip_rect: Option<ClipRect>,
     },
 }
 
-struct VertexCtor {
-    color: u32,
-    transform: Affine2,
-}
-
-impl VertexCtor {
-    fn new(color: i32, transform: Affine2) -> Self {
-        Self {
-            color: unsafe { std::mem::transmute(color) },
-            transform,
+impl WgpugdCommand {
+    pub(crate) fn draw_command(&self) -> Option<&DrawCommand> {
+        match self {
+            WgpugdCommand::DrawPolygon(cmd)
+            | WgpugdCommand::DrawSDF(cmd)
+            | WgpugdCommand::DrawStroke(cmd)
+            | WgpugdCommand::DrawGlyph(cmd)
+            | WgpugdCommand::DrawAtlasGlyph(cmd) => Some(cmd),
+            WgpugdCommand::SetClipping { .. } => None,
         }
     }
-}
-
-impl StrokeVertexConstructor<crate::Vertex> for VertexCtor {
-    fn new_vertex(&mut self, vertex: StrokeVertex) -> crate::Vertex {
-        let position_orig: mint::Point2<_> = vertex.position().into();
-        let position = self.transform.transform_point2(position_orig.into());
 
-        crate::Vertex {
-            position: position.into(),
-            color: self.color,
+    pub(crate) fn draw_command_mut(&mut self) -> Option<&mut DrawCommand> {
+        match self {
+            WgpugdCommand::DrawPolygon(cmd)
+            | WgpugdCommand::DrawSDF(cmd)
+            | WgpugdCommand::DrawStroke(cmd)
+            | WgpugdCommand::DrawGlyph(cmd)
+            | WgpugdCommand::DrawAtlasGlyph(cmd) => Some(cmd),
+            WgpugdCommand::SetClipping { .. } => None,
         }
     }
 }
 
-impl FillVertexConstructor<crate::Vertex> for VertexCtor {
-    fn new_vertex(&mut self, vertex: FillVertex) -> crate::Vertex {
-        let position_orig: mint::Point2<_> = vertex.position().into();
-        let position = self.transform.transform_point2(position_orig.into());
-
-        crate::Vertex {
-            position: position.into(),
-            color: self.color,
+impl crate::WgpuGraphicsDevice {
+    // The width and the color of the stroke, as a hairline if it's thinner
+    // than a pixel in the hairline mode.
+    fn stroke_style(&self, gc: &R_GE_gcontext) -> (f32, i32) {
+        let line_width = translate_line_width(gc.lwd);
+        if self.options.hairline {
+            hairline(line_width, gc.col)
+        } else {
+            (line_width, gc.col)
         }
     }
-}
 
-impl crate::WgpuGraphicsDevice {
+    // The tessellation tolerance (in points) for a shape of the size
+    fn tolerance(&self, size: f32) -> f32 {
+        self.options
+            .tolerance_in_points()
+            .max(size * MIN_RELATIVE_TOLERANCE)
+    }
+
     fn tesselate_path_stroke(&mut self, path: &Path, stroke_options: &StrokeOptions, color: i32) {
         self.tesselate_path_stroke_with_transform(
             path,
@@ -103,37 +147,12 @@ impl crate::WgpuGraphicsDevice {
             return;
         }
 
-        let mut stroke_tess = StrokeTessellator::new();
-
-        let ctxt = VertexCtor::new(color, transform);
-
-        let count = stroke_tess
-            .tessellate_path(
-                path,
-                stroke_options,
-                &mut BuffersBuilder::new(&mut self.geometry, ctxt),
-            )
-            .unwrap();
-
-        match self.current_command {
-            // If the previous command was the same, squash them into one draw
-            // command.
-            Some(WgpugdCommand::DrawPolygon(ref mut cmd)) => {
-                cmd.extend(count.indices);
-            }
-            // If the previous command was different, push it to the command
-            // queue (if exists) and create a new command.
-            _ => {
-                let prev = self
-                    .current_command
-                    .replace(WgpugdCommand::DrawPolygon(DrawCommand {
-                        count: count.indices,
-                    }));
-                if let Some(prev_cmd) = prev {
-                    self.command_queue.push(prev_cmd)
-                }
-            }
-        }
+        self.push_tessellation_job(TessellationJob {
+            shape: TessellationShape::Path(path.clone()),
+            style: TessellationStyle::Stroke(*stroke_options),
+            color,
+            transform,
+        });
     }
 
     fn tesselate_path_fill(&mut self, path: &Path, fill_options: &FillOptions, color: i32) {
@@ -151,37 +170,12 @@ impl crate::WgpuGraphicsDevice {
             return;
         }
 
-        let mut fill_tess = FillTessellator::new();
-
-        let ctxt = VertexCtor::new(color, transform);
-
-        let count = fill_tess
-            .tessellate_path(
-                path,
-                fill_options,
-                &mut BuffersBuilder::new(&mut self.geometry, ctxt),
-            )
-            .unwrap();
-
-        match self.current_command {
-            // If the previous command was the same, squash them into one draw
-            // command.
-            Some(WgpugdCommand::DrawPolygon(ref mut cmd)) => {
-                cmd.extend(count.indices);
-            }
-            // If the previous command was different, push it to the command
-            // queue (if exists) and create a new command.
-            _ => {
-                let prev = self
-                    .current_command
-                    .replace(WgpugdCommand::DrawPolygon(DrawCommand {
-                        count: count.indices,
-                    }));
-                if let Some(prev_cmd) = prev {
-                    self.command_queue.push(prev_cmd)
-                }
-            }
-        }
+        self.push_tessellation_job(TessellationJob {
+            shape: TessellationShape::Path(path.clone()),
+            style: TessellationStyle::Fill(*fill_options),
+            color,
+            transform,
+        });
     }
 
     fn tesselate_rect_stroke(
@@ -194,37 +188,12 @@ impl crate::WgpuGraphicsDevice {
             return;
         }
 
-        let mut stroke_tess = StrokeTessellator::new();
-
-        let ctxt = VertexCtor::new(color, glam::Affine2::IDENTITY);
-
-        let count = stroke_tess
-            .tessellate_rectangle(
-                rect,
-                stroke_options,
-                &mut BuffersBuilder::new(&mut self.geometry, ctxt),
-            )
-            .unwrap();
-
-        match self.current_command {
-            // If the previous command was the same, squash them into one draw
-            // command.
-            Some(WgpugdCommand::DrawPolygon(ref mut cmd)) => {
-                cmd.extend(count.indices);
-            }
-            // If the previous command was different, push it to the command
-            // queue (if exists) and create a new command.
-            _ => {
-                let prev = self
-                    .current_command
-                    .replace(WgpugdCommand::DrawPolygon(DrawCommand {
-                        count: count.indices,
-                    }));
-                if let Some(prev_cmd) = prev {
-                    self.command_queue.push(prev_cmd)
-                }
-            }
-        }
+        self.push_tessellation_job(TessellationJob {
+            shape: TessellationShape::Rect(*rect),
+            style: TessellationStyle::Stroke(*stroke_options),
+            color,
+            transform: glam::Affine2::IDENTITY,
+        });
     }
 
     fn tesselate_rect_fill(
@@ -237,37 +206,250 @@ impl crate::WgpuGraphicsDevice {
             return;
         }
 
-        let mut fill_tess = FillTessellator::new();
+        self.push_tessellation_job(TessellationJob {
+            shape: TessellationShape::Rect(*rect),
+            style: TessellationStyle::Fill(*fill_options),
+            color,
+            transform: glam::Affine2::IDENTITY,
+        });
+    }
+
+    // The job is tessellated later in render().
+    fn push_tessellation_job(&mut self, job: TessellationJob) {
+        let bbox = job.bounds();
+        self.tessellation_bytes += job.estimated_bytes();
+        self.tessellation_jobs.push(job);
+
+        push_command(
+            &mut self.command_queue,
+            WgpugdCommand::DrawPolygon(DrawCommand::new(1, bbox)),
+        );
+        self.flush_if_needed();
+    }
 
-        let ctxt = VertexCtor::new(color, glam::Affine2::IDENTITY);
+    fn push_sdf_instance(&mut self, instance: crate::SDFInstance) {
+        let bbox = instance.bounds();
+        self.sdf_instances.push(instance);
 
-        let count = fill_tess
-            .tessellate_rectangle(
-                rect,
-                fill_options,
-                &mut BuffersBuilder::new(&mut self.geometry, ctxt),
-            )
-            .unwrap();
+        push_command(
+            &mut self.command_queue,
+            WgpugdCommand::DrawSDF(DrawCommand::new(1, bbox)),
+        );
+        self.flush_if_needed();
+    }
 
-        match self.current_command {
-            // If the previous command was the same, squash them into one draw
-            // command.
-            Some(WgpugdCommand::DrawPolygon(ref mut cmd)) => {
-                cmd.extend(count.indices);
+    fn push_stroke<T: IntoIterator<Item = (f64, f64)>>(
+        &mut self,
+        coords: T,
+        color: i32,
+        line_width: f32,
+        line_cap: lyon::tessellation::LineCap,
+    ) {
+        let begin = self.stroke_points.len();
+        let count = push_polyline(&mut self.stroke_points, coords, line_width, line_cap, color);
+
+        // The corners of a square cap stick out by sqrt(2) times the half
+        // width, plus 1 pixel for anti-aliasing (the same as
+        // `TessellationJob::bounds()`).
+        let bbox = BoundingBox::from_points(
+            self.stroke_points[begin..]
+                .iter()
+                .map(|p| (p.position[0], p.position[1])),
+        )
+        .expand(line_width / 2.0 * std::f32::consts::SQRT_2 + 1.0);
+
+        push_command(
+            &mut self.command_queue,
+            WgpugdCommand::DrawStroke(DrawCommand::new(count, bbox)),
+        );
+        self.flush_if_needed();
+    }
+
+    // An axis-aligned line is a (rounded) rect, and a diagonal line is a rect
+    // rotated by 45 degrees. Returns false if the line is neither.
+    fn push_line_as_rect(
+        &mut self,
+        from: (f64, f64),
+        to: (f64, f64),
+        color: i32,
+        line_width: f32,
+        line_cap: lyon::tessellation::LineCap,
+    ) -> bool {
+        let (dx, dy) = (to.0 - from.0, to.1 - from.1);
+        let vertical = dx == 0.0;
+        // e.g. pch 4 draws `x ± xc` and `y ± xc`, which might differ slightly
+        let diagonal = dx != 0.0 && (dx.abs() - dy.abs()).abs() < dx.abs() * 1e-6;
+        if !vertical && !diagonal && dy != 0.0 {
+            return false;
+        }
+
+        // Snap the line across its width
+        let (from, to) = if self.options.snap && !diagonal {
+            if vertical {
+                let x = snap_stroke_center(from.0, line_width);
+                ((x, from.1), (x, to.1))
+            } else {
+                let y = snap_stroke_center(from.1, line_width);
+                ((from.0, y), (to.0, y))
+            }
+        } else {
+            (from, to)
+        };
+
+        let half_width = line_width as f64 / 2.0;
+        let half_length = if diagonal {
+            dx.hypot(dy) / 2.0
+        } else {
+            ((to.0 - from.0).abs() + (to.1 - from.1).abs()) / 2.0
+        };
+        let (shape, half_length, corner_radius) = match line_cap {
+            lyon::tessellation::LineCap::Butt => (SdfShape::Rect, half_length, 0.0),
+            lyon::tessellation::LineCap::Square => {
+                (SdfShape::Rect, half_length + half_width, 0.0)
+            }
+            lyon::tessellation::LineCap::Round => {
+                (SdfShape::RoundedRect, half_length + half_width, half_width)
+            }
+        };
+
+        let center = ((from.0 + to.0) / 2.0, (from.1 + to.1) / 2.0);
+        // The diagonal shape is rounded by `corner_radius` regardless of the
+        // cap. Its half size is along the diagonals, so it's similar to a
+        // vertical line if the line goes from the top left to the bottom right.
+        let (shape, vertical) = if diagonal {
+            (SdfShape::Diagonal, dx * dy < 0.0)
+        } else {
+            (shape, vertical)
+        };
+        let half_size = if vertical {
+            (half_width, half_length)
+        } else {
+            (half_length, half_width)
+        };
+
+        let mut instance =
+            crate::SDFInstance::new(shape, center, half_size, 0.0, color, i32::na());
+        instance.corner_radius = corner_radius as _;
+
+        // If this crosses the previous line at the center, e.g. pch 3 and 4,
+        // merge them into a plus or a cross. The previous line is in the last
+        // SDF command, so this is fine as long as this line can be merged into
+        // the command.
+        let new_cmd = WgpugdCommand::DrawSDF(DrawCommand::new(0, instance.bounds()));
+        if let (Some(i), Some(prev)) = (
+            find_batch(&self.command_queue, &new_cmd),
+            self.sdf_instances.last_mut(),
+        ) {
+            let is_perpendicular_pair = prev.shape == shape as u32
+                && prev.fill_color == crate::sdf_color(color)
+                && prev.stroke_width == 0.0
+                && prev.corner_radius == corner_radius as f32
+                && prev.center == [center.0 as f32, center.1 as f32]
+                && prev.half_size == [half_size.1 as f32, half_size.0 as f32];
+
+            if is_perpendicular_pair {
+                prev.arm_width = half_width as _;
+                prev.half_size = [half_length as _, half_length as _];
+                prev.shape = if diagonal {
+                    SdfShape::Cross as _
+                } else {
+                    SdfShape::Plus as _
+                };
+                if let (Some(cmd), Some(new_cmd)) = (
+                    self.command_queue[i].draw_command_mut(),
+                    new_cmd.draw_command(),
+                ) {
+                    cmd.merge(new_cmd);
+                }
+                return true;
             }
-            // If the previous command was different, push it to the command
-            // queue (if exists) and create a new command.
-            _ => {
-                let prev = self
-                    .current_command
-                    .replace(WgpugdCommand::DrawPolygon(DrawCommand {
-                        count: count.indices,
-                    }));
-                if let Some(prev_cmd) = prev {
-                    self.command_queue.push(prev_cmd)
+        }
+
+        self.push_sdf_instance(instance);
+
+        true
+    }
+
+    fn push_glyph_instance(&mut self, mesh: &GlyphMesh, transform: Affine2, color: i32) {
+        let id = self.glyph_instances.len() as u32;
+        self.glyph_instances.push(crate::GlyphInstance::new(transform, color));
+
+        let new_cmd = WgpugdCommand::DrawGlyph(DrawCommand::new(
+            1,
+            mesh.bounds.transform(transform).expand(1.0),
+        ));
+
+        // If the glyph can be merged into the last glyph command and the last
+        // glyph was also the same, squash them into one run.
+        let batch = find_batch(&self.command_queue, &new_cmd);
+        if let (Some(i), Some(run)) = (batch, self.glyph_runs.last_mut()) {
+            if run.indices == mesh.indices {
+                run.instances.end += 1;
+
+                if let (Some(cmd), Some(new_cmd)) = (
+                    self.command_queue[i].draw_command_mut(),
+                    new_cmd.draw_command(),
+                ) {
+                    cmd.bbox = cmd.bbox.union(new_cmd.bbox);
                 }
+                return;
             }
         }
+
+        self.glyph_runs.push(GlyphRun {
+            indices: mesh.indices.clone(),
+            instances: id..(id + 1),
+        });
+        push_command(&mut self.command_queue, new_cmd);
+        self.flush_if_needed();
+    }
+
+    // Returns false if the glyph is not in the atlas (e.g. the atlas is full),
+    // so that the caller can fall back to the glyph mesh.
+    #[allow(clippy::too_many_arguments)]
+    fn push_atlas_glyph(
+        &mut self,
+        entry: &AtlasEntry,
+        glyph_transform: Affine2,
+        size: f32,
+        darken: bool,
+        color: i32,
+    ) {
+        // Map the unit square to the quad on the `SDF_GLYPH_SIZE`, and then
+        // scale it to the actual size.
+        let quad_transform = glyph_transform
+            * Affine2::from_scale(glam::Vec2::splat(size / SDF_GLYPH_SIZE))
+            * Affine2::from_scale_angle_translation(
+                glam::vec2(entry.width as _, entry.height as _),
+                0.0,
+                entry.origin.into(),
+            );
+
+        // Move the edge outward by the half on each side
+        let bias = if darken {
+            distance_bias(STEM_DARKENING / 2.0, size)
+        } else {
+            0.0
+        };
+
+        // The position in the atlas is in texels; the atlas can grow before
+        // the instance is drawn, so it's normalized in the shader.
+        self.atlas_instances.push(crate::AtlasGlyphInstance::new(
+            quad_transform,
+            [entry.x as _, entry.y as _],
+            [entry.width as _, entry.height as _],
+            color,
+            bias,
+        ));
+
+        let bbox = BoundingBox::from_points([(0.0, 0.0), (1.0, 1.0)])
+            .transform(quad_transform)
+            .expand(1.0);
+        push_command(
+            &mut self.command_queue,
+            WgpugdCommand::DrawAtlasGlyph(DrawCommand::new(1, bbox)),
+        );
+        self.flush_if_needed();
     }
 
     // This handles polygon(), polyline(), and line().
@@ -302,18 +484,21 @@ impl crate::WgpuGraphicsDevice {
 
         let path = builder.build();
 
+        let bbox = lyon::algorithms::aabb::fast_bounding_rect(path.iter());
+        let tolerance = self.tolerance(bbox.width().max(bbox.height()));
+
         //
         // **** Tessellate fill ***************************
         //
 
-        let fill_options = &FillOptions::tolerance(DEFAULT_TOLERANCE);
+        let fill_options = &FillOptions::tolerance(tolerance);
         self.tesselate_path_fill(&path, fill_options, fill);
 
         //
         // **** Tessellate stroke ***************************
         //
 
-        let stroke_options = &StrokeOptions::tolerance(DEFAULT_TOLERANCE)
+        let stroke_options = &StrokeOptions::tolerance(tolerance)
             .with_line_width(line_width)
             .with_line_cap(line_cap)
             .with_line_join(line_join)
@@ -355,12 +540,21 @@ impl DeviceDriver for crate::WgpuGraphicsDevice {
     const CLIPPING_STRATEGY: ClippingStrategy = ClippingStrategy::Device;
 
     fn line(&mut self, from: (f64, f64), to: (f64, f64), gc: R_GE_gcontext, _: DevDesc) {
-        let color = gc.col;
-        let line_width = translate_line_width(gc.lwd);
+        let (line_width, color) = self.stroke_style(&gc);
         let line_cap = translate_line_cap(gc.lend);
         let line_join = translate_line_join(gc.ljoin);
         let mitre_limit = gc.lmitre as f32;
 
+        if color.is_na() || self.push_line_as_rect(from, to, color, line_width, line_cap) {
+            return;
+        }
+
+        // A single segment has no joins, so this can always be drawn on GPU
+        if line_width > 0.0 {
+            self.push_stroke([from, to], color, line_width, line_cap);
+            return;
+        }
+
         self.polygon_inner(
             [from, to],
             color,
@@ -379,12 +573,42 @@ impl DeviceDriver for crate::WgpuGraphicsDevice {
         gc: R_GE_gcontext,
         _: DevDesc,
     ) {
-        let color = gc.col;
-        let line_width = translate_line_width(gc.lwd);
+        let (line_width, color) = self.stroke_style(&gc);
         let line_cap = translate_line_cap(gc.lend);
         let line_join = translate_line_join(gc.ljoin);
         let mitre_limit = gc.lmitre as f32;
 
+        if color.is_na() {
+            return;
+        }
+
+        let mut coords: Vec<(f64, f64)> = coords.into_iter().collect();
+
+        // A polyline of 2 points is just a line (e.g. gridlines)
+        if coords.len() == 2
+            && self.push_line_as_rect(coords[0], coords[1], color, line_width, line_cap)
+        {
+            return;
+        }
+
+        // The device coordinates are in points, and a point is `supersample`
+        // columns of pixels in the framebuffer.
+        if self.options.decimate {
+            let column_width = 1.0 / self.options.supersample as f64;
+            if let Some(decimated) = decimate_polyline(&coords, column_width) {
+                coords = decimated;
+            }
+        }
+
+        // The segments are joined by overlapping round ends, so fall back to
+        // lyon if the joins are not round, or if the overlaps are visible.
+        let is_opaque = (color as u32) >> 24 == 255;
+        let is_round = line_join == lyon::tessellation::LineJoin::Round;
+        if line_width > 0.0 && (coords.len() <= 2 || (is_round && is_opaque)) {
+            self.push_stroke(coords, color, line_width, line_cap);
+            return;
+        }
+
         self.polygon_inner(
             coords,
             color,
@@ -403,13 +627,36 @@ impl DeviceDriver for crate::WgpuGraphicsDevice {
         gc: R_GE_gcontext,
         _: DevDesc,
     ) {
-        let color = gc.col;
         let fill = gc.fill;
-        let line_width = translate_line_width(gc.lwd);
+        let (line_width, color) = self.stroke_style(&gc);
         let line_cap = translate_line_cap(gc.lend);
         let line_join = translate_line_join(gc.ljoin);
         let mitre_limit = gc.lmitre as f32;
 
+        let coords: Vec<(f64, f64)> = coords.into_iter().collect();
+
+        // Triangles and diamonds are typically markers (e.g. pch 2 and 5).
+        // The stroke of SDF shapes has round joins.
+        if color.is_na() || line_join == lyon::tessellation::LineJoin::Round {
+            let marker = match coords.len() {
+                3 => detect_triangle(&coords).map(|b| (SdfShape::Triangle, b)),
+                4 => detect_diamond(&coords).map(|b| (SdfShape::Diamond, b)),
+                _ => None,
+            };
+
+            if let Some((shape, b)) = marker {
+                self.push_sdf_instance(crate::SDFInstance::new(
+                    shape,
+                    b.center,
+                    b.half_size,
+                    line_width,
+                    fill,
+                    color,
+                ));
+                return;
+            }
+        }
+
         self.polygon_inner(
             coords,
             color,
@@ -423,62 +670,87 @@ impl DeviceDriver for crate::WgpuGraphicsDevice {
     }
 
     fn circle(&mut self, center: (f64, f64), r: f64, gc: R_GE_gcontext, _: DevDesc) {
-        let color = gc.col;
         let fill = gc.fill;
-        let line_width = translate_line_width(gc.lwd);
+        let (line_width, color) = self.stroke_style(&gc);
 
-        self.sdf_instances.push(crate::SDFInstance {
-            center: [center.0 as _, center.1 as _],
-            radius: r as _,
-            stroke_width: line_width,
-            fill_color: unsafe { std::mem::transmute(fill) },
-            stroke_color: unsafe { std::mem::transmute(color) },
-        });
-
-        match self.current_command {
-            // If the previous command was the same, squash them into one draw
-            // command.
-            Some(WgpugdCommand::DrawSDF(ref mut cmd)) => {
-                cmd.extend(1);
-            }
-            // If the previous command was different, push it to the command
-            // queue (if exists) and create a new command.
-            _ => {
-                let prev = self
-                    .current_command
-                    .replace(WgpugdCommand::DrawSDF(DrawCommand { count: 1 }));
-                if let Some(prev_cmd) = prev {
-                    self.command_queue.push(prev_cmd)
-                }
-            }
-        }
+        self.push_sdf_instance(crate::SDFInstance::new(
+            SdfShape::Circle,
+            center,
+            (r, r),
+            line_width,
+            fill,
+            color,
+        ));
     }
 
     fn rect(&mut self, from: (f64, f64), to: (f64, f64), gc: R_GE_gcontext, _: DevDesc) {
-        let color = gc.col;
         let fill = gc.fill;
-        let line_width = translate_line_width(gc.lwd);
+        let (line_width, color) = self.stroke_style(&gc);
         let line_cap = translate_line_cap(gc.lend);
         let line_join = translate_line_join(gc.ljoin);
         let mitre_limit = gc.lmitre as f32;
 
+        // Snap the edges, or the stroke on them, to the pixel grid
+        let (from, to) = if self.options.snap {
+            let stroke_width = if color.is_na() || line_width <= 0.0 {
+                None
+            } else {
+                Some(line_width)
+            };
+            let (x0, x1) = snap_span(from.0, to.0, stroke_width);
+            let (y0, y1) = snap_span(from.1, to.1, stroke_width);
+            ((x0, y0), (x1, y1))
+        } else {
+            (from, to)
+        };
+
+        // Small rects (e.g. square markers) are drawn as SDF shapes unless the
+        // stroke needs bevel joins. A mitre join of a right angle is sqrt(2)
+        // times as long as the line width, so a smaller mitre limit bevels it.
+        let is_small = (to.0 - from.0).abs().max((to.1 - from.1).abs()) <= MAX_SDF_RECT_SIZE;
+        let is_mitre = line_join == lyon::tessellation::LineJoin::Miter
+            && mitre_limit >= std::f32::consts::SQRT_2;
+        let shape = if !is_small {
+            None
+        } else if color.is_na() || is_mitre {
+            Some(SdfShape::Rect)
+        } else if line_join == lyon::tessellation::LineJoin::Round {
+            Some(SdfShape::RoundedRect)
+        } else {
+            None
+        };
+
+        if let Some(shape) = shape {
+            self.push_sdf_instance(crate::SDFInstance::new(
+                shape,
+                ((from.0 + to.0) / 2.0, (from.1 + to.1) / 2.0),
+                ((to.0 - from.0).abs() / 2.0, (to.1 - from.1).abs() / 2.0),
+                line_width,
+                fill,
+                color,
+            ));
+            return;
+        }
+
         let x = from.0.min(to.0) as f32;
         let y = from.1.min(to.1) as f32;
         let w = (to.0 - from.0).abs() as f32;
         let h = (to.1 - from.1).abs() as f32;
 
+        let tolerance = self.tolerance(w.max(h));
+
         //
         // **** Tessellate fill ***************************
         //
 
-        let fill_options = &FillOptions::tolerance(DEFAULT_TOLERANCE);
+        let fill_options = &FillOptions::tolerance(tolerance);
         self.tesselate_rect_fill(&lyon::math::rect(x, y, w, h), fill_options, fill);
 
         //
         // **** Tessellate stroke ***************************
         //
 
-        let stroke_options = &StrokeOptions::tolerance(DEFAULT_TOLERANCE)
+        let stroke_options = &StrokeOptions::tolerance(tolerance)
             .with_line_width(line_width)
             .with_line_cap(line_cap)
             .with_line_join(line_join)
@@ -487,15 +759,14 @@ impl DeviceDriver for crate::WgpuGraphicsDevice {
         self.tesselate_rect_stroke(&lyon::math::rect(x, y, w, h), stroke_options, color);
     }
 
-    // Wildly assumes 1 font has 1pt of width, and 10% of horizontal margins on
-    // top and bottom. This should be properly done by querying to a font
-    // database (e.g. https://github.com/RazrFalcon/fontdb).
     fn char_metric(&mut self, c: char, gc: R_GE_gcontext, _: DevDesc) -> TextMetric {
+        stats::CHAR_METRIC_CALLS.incr();
+
         let fontfamily =
             unsafe { std::ffi::CStr::from_ptr(&gc.fontfamily as *const c_char) }.to_string_lossy();
 
-        let id = match crate::text::FONTDB.query(&fontfamily, gc.fontface) {
-            Some(id) => id,
+        let face = match self.font_cache.face(&fontfamily, gc.fontface) {
+            Some(face) => face,
             None => {
                 reprintln!("[WARN] No fallback font found, aborting");
                 return TextMetric {
@@ -506,31 +777,7 @@ impl DeviceDriver for crate::WgpuGraphicsDevice {
             }
         };
 
-        FONTDB
-            .with_face_data(id, |font_data, face_index| {
-                let font = ttf_parser::Face::from_slice(font_data, face_index).unwrap();
-                let scale = gc.cex * gc.ps / font.height() as f64;
-
-                let glyph_id = font.glyph_index(c).unwrap_or(GlyphId(0));
-
-                match font.glyph_bounding_box(glyph_id) {
-                    Some(bbox) => TextMetric {
-                        ascent: bbox.y_max as f64 * scale,
-                        descent: bbox.y_min as f64 * scale,
-                        width: font
-                            .glyph_hor_advance(glyph_id)
-                            .unwrap_or(bbox.width() as _) as f64
-                            * scale,
-                    },
-                    // If the glyph info is not available, use font info
-                    _ => TextMetric {
-                        ascent: font.ascender() as f64 * scale,
-                        descent: font.descender() as f64 * scale,
-                        width: font.height() as f64 * scale,
-                    },
-                }
-            })
-            .unwrap()
+        self.font_cache.metric(&face, c, gc.cex * gc.ps)
     }
 
     fn text(
@@ -543,130 +790,163 @@ impl DeviceDriver for crate::WgpuGraphicsDevice {
         _: DevDesc,
     ) {
         let fill = gc.col;
+        if fill.is_na() {
+            return;
+        }
 
         let fontfamily =
             unsafe { std::ffi::CStr::from_ptr(&gc.fontfamily as *const c_char) }.to_string_lossy();
 
-        let id = match crate::text::FONTDB.query(&fontfamily, gc.fontface) {
-            Some(id) => id,
+        let face = match self.font_cache.face(&fontfamily, gc.fontface) {
+            Some(face) => face,
             None => {
                 reprintln!("[WARN] No fallback font found, aborting");
                 return;
             }
         };
 
-        FONTDB.with_face_data(id, |font_data, face_index| {
-            let font = ttf_parser::Face::from_slice(font_data, face_index).unwrap();
-
-            let facetables = font.tables();
-
-            // Deviding by `height` is to normalize the font coordinates to 1.
-            // Then, multiply by `cex` (size of the font in device specific
-            // unit) and `px` (pointsize, should be 12) to convert to the value
-            // in points. Since the range of the values actually matters on
-            // tessellation, we need to multiply before tessellation.
-            let scale = (gc.cex * gc.ps) as f32 / font.height() as f32;
-
-            let mut builder = crate::text::LyonOutlineBuilder::new(scale);
+        // Deviding by `height` is to normalize the font coordinates to 1. Then,
+        // multiply by `cex` (size of the font in device specific unit) and `px`
+        // (pointsize, should be 12) to convert to the value in points.
+        let size = (gc.cex * gc.ps) as f32;
+
+        // The hinting-like adjustments. The snapping is done only for unrotated
+        // texts. Note that 1 point is 1 pixel on this device.
+        let snap = self.options.snap_text && angle % 360.0 == 0.0;
+        let darken = self.options.snap_text && size < STEM_DARKENING_MAX_SIZE;
+
+        // The font database is locked only to extract the outlines. Drawing the
+        // glyphs can flush the commands, which takes a while, so it's done after
+        // unlocking.
+        let placed_glyphs = FONTDB
+            .lock()
+            .unwrap()
+            .with_face_data(face.id, |font_data, face_index| {
+                let font = parse_face(font_data, face_index, &face.spec).unwrap();
+                let scale = size / font.height() as f32;
+
+                // Layout the glyphs first, as the offset by `hadj` needs the
+                // total width. The offsets are in the font units.
+                let (glyphs, advance) = layout_glyphs(&font, text);
+                let width = advance * scale;
+
+                // Scale vertically so that the x-height is a whole number of
+                // pixels
+                let scale_y = match font.x_height() {
+                    Some(x_height) if snap => x_height_scale(x_height as f32 * scale),
+                    _ => 1.0,
+                };
 
-            let mut prev_glyph: Option<GlyphId> = None;
-            for c in text.chars() {
-                // Skip control characters. Note that it seems linebreaks are
-                // handled on R's side, so we don't need to care about multiline
-                // cases.
-                if c.is_control() {
-                    prev_glyph = None;
-                    continue;
+                // First, move the origin depending on `hadj`
+                let transform_hadj =
+                    glam::Affine2::from_translation(glam::vec2(width * -hadj as f32, 0.0));
+
+                // Second, rotate and translate to the position
+                let transform = glam::Affine2::from_angle_translation(
+                    angle as f32 / 360.0 * 2. * PI,
+                    glam::vec2(pos.0 as _, pos.1 as _),
+                ) * transform_hadj;
+
+                let mut placed_glyphs = Vec::with_capacity(glyphs.len());
+                for (glyph_id, x) in glyphs {
+                    // The transform to place the glyph at the actual size
+                    let glyph_transform = if snap {
+                        let origin_x = pos.0 as f32 - width * hadj as f32 + x * scale;
+                        Affine2::from_scale_angle_translation(
+                            glam::vec2(1.0, scale_y),
+                            0.0,
+                            snap_glyph_origin(origin_x, pos.1 as _),
+                        )
+                    } else {
+                        transform * Affine2::from_translation(glam::vec2(x * scale, 0.0))
+                    };
+
+                    if self.options.text_mode == TextMode::Atlas {
+                        if let Some(entry) = self.sdf_atlas.get_or_insert(face.key, &font, glyph_id)
+                        {
+                            placed_glyphs.push(PlacedGlyph::Atlas(entry, glyph_transform));
+                            continue;
+                        }
+                    }
+
+                    match self
+                        .glyph_meshes
+                        .get_or_insert(face.key, &font, glyph_id, size, darken)
+                    {
+                        Some(mesh) => placed_glyphs.push(PlacedGlyph::Mesh(mesh, glyph_transform)),
+                        // e.g. a space
+                        None => continue,
+                    }
                 }
+                placed_glyphs
+            })
+            .unwrap_or_default();
 
-                // Even when we cannot find glyph_id, fill it with 0.
-                let cur_glyph = font.glyph_index(c).unwrap_or(GlyphId(0));
-
-                if let Some(prev_glyph) = prev_glyph {
-                    builder.add_offset_x(crate::text::find_kerning(
-                        facetables, prev_glyph, cur_glyph,
-                    ) as _);
+        for placed_glyph in placed_glyphs {
+            match placed_glyph {
+                PlacedGlyph::Atlas(entry, glyph_transform) => {
+                    self.push_atlas_glyph(&entry, glyph_transform, size, darken, fill);
                 }
-
-                font.outline_glyph(cur_glyph, &mut builder);
-
-                if let Some(ha) = font.glyph_hor_advance(cur_glyph) {
-                    builder.add_offset_x(ha as _);
+                PlacedGlyph::Mesh(mesh, glyph_transform) => {
+                    // The mesh is tessellated on a different size, so scale it
+                    let mesh_transform =
+                        glyph_transform * Affine2::from_scale(glam::Vec2::splat(size / mesh.size));
+                    self.push_glyph_instance(&mesh, mesh_transform, fill);
                 }
-
-                prev_glyph = Some(cur_glyph);
             }
-
-            // First, move the origin depending on `hadj`
-            let transform_hadj =
-                glam::Affine2::from_translation(glam::vec2(builder.offset_x() * -hadj as f32, 0.0));
-
-            // Second, rotate and translate to the position
-            let transform = glam::Affine2::from_angle_translation(
-                angle as f32 / 360.0 * 2. * PI,
-                glam::vec2(pos.0 as _, pos.1 as _),
-            ) * transform_hadj;
-
-            let path = builder.build();
-
-            //
-            // **** Tessellate fill ***************************
-            //
-
-            let fill_options = &FillOptions::tolerance(DEFAULT_TOLERANCE);
-            self.tesselate_path_fill_with_transform(&path, fill_options, fill, transform);
-        });
+        }
     }
 
     fn clip(&mut self, from: (f64, f64), to: (f64, f64), _: DevDesc) {
-        let x0 = from.0.clamp(0.0, self.width as _);
-        let x1 = to.0.clamp(0.0, self.width as _);
-        let y0 = from.1.clamp(0.0, self.height as _);
-        let y1 = to.1.clamp(0.0, self.height as _);
+        let ([x, y, width, height], clip_rect) = clip_region(
+            from,
+            to,
+            self.width,
+            self.height,
+            self.options.supersample,
+            self.options.exact_clip,
+        );
 
         let cmd = WgpugdCommand::SetClipping {
-            x: x0.min(x1) as u32,
-            // Y-axis is upside down
-            y: self.height - y0.max(y1) as u32,
-            width: (x0 - x1).abs() as u32,
-            height: (y0 - y1).abs() as u32,
+            x,
+            y,
+            width,
+            height,
+            clip_rect,
         };
 
-        let prev = self.current_command.replace(cmd);
-        match prev {
-            Some(WgpugdCommand::SetClipping { .. }) => {
-                // If the new command just replacing the current clipping, do
-                // nothing; just discard the old one.
-            }
-            Some(prev_cmd_inner) => {
-                self.command_queue.push(prev_cmd_inner);
-            }
-            None => {
-                // Do nothing
-            }
+        match self.command_queue.last_mut() {
+            // If the new command just replacing the current clipping, discard
+            // the old one.
+            Some(last @ WgpugdCommand::SetClipping { .. }) => *last = cmd,
+            _ => self.command_queue.push(cmd),
         }
     }
 
-    fn new_page(&mut self, _: R_GE_gcontext, _: DevDesc) {
+    fn new_page(&mut self, gc: R_GE_gcontext, _: DevDesc) {
         // newPage() is called soon after the device is open, but there's
         // nothing to render. So, skip rendering at first.
         if self.cur_page != 0 {
-            self.render().unwrap();
-
-            pollster::block_on(self.write_png());
+            if let Err(e) = self.render() {
+                reprintln!("[ERROR] {e:?}");
+            }
 
-            self.current_command = None;
-            self.command_queue.clear();
-            self.geometry.indices.clear();
-            self.geometry.vertices.clear();
-            self.sdf_instances.clear();
+            self.clear_commands();
         }
 
         self.cur_page += 1;
+        // The fill is the background of the new page
+        self.page_background = gc.fill;
     }
 
     fn close(&mut self, _: DevDesc) {
-        self.render().unwrap();
-        pollster::block_on(self.write_png());
+        if let Err(e) = self.render() {
+            reprintln!("[ERROR] {e:?}");
+        }
+
+        // Wait for the pages to be written
+        if let Err(e) = self.image_writer.finish() {
+            reprintln!("[ERROR] {e}");
+        }
     }
 }
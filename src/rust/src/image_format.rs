@@ -0,0 +1,314 @@
+// The output format is chosen by the extension of the filename. The rendered
+// image is RGBA with the alpha premultiplied, on the page background. The
+// formats with alpha store it unpremultiplied, and the ones without alpha (JPEG
+// and BMP) get it composited onto the opaque page background, or onto white if
+// the background is transparent.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use extendr_api::prelude::*;
+use image::codecs::{bmp::BmpEncoder, jpeg::JpegEncoder, tiff::TiffEncoder};
+use image::ColorType;
+
+use crate::image_writer::ImageLayout;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum ImageFormat {
+    Png,
+    // The quality is between 1 and 100
+    Jpeg { quality: u8 },
+    // The quality is ignored if lossless
+    WebP { lossless: bool, quality: u8 },
+    Tiff,
+    Bmp,
+}
+
+impl ImageFormat {
+    pub(crate) fn from_path(path: &Path, quality: u8, lossless: bool) -> Result<Self> {
+        let extension = path
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+
+        match extension.as_str() {
+            "png" => Ok(Self::Png),
+            "jpg" | "jpeg" => Ok(Self::Jpeg { quality }),
+            "webp" => Ok(Self::WebP { lossless, quality }),
+            "tif" | "tiff" => Ok(Self::Tiff),
+            "bmp" => Ok(Self::Bmp),
+            _ => Err(Error::Other(format!(
+                "Unsupported file extension: {path:?}. \
+                 The filename must end with .png, .jpg, .jpeg, .webp, .tif, .tiff, or .bmp"
+            ))),
+        }
+    }
+}
+
+// Converts R's color of the page background into the premultiplied color to
+// clear the framebuffer with. NA is transparent.
+pub(crate) fn clear_color(color: i32) -> wgpu::Color {
+    if color.is_na() {
+        return wgpu::Color::TRANSPARENT;
+    }
+
+    let color = color as u32;
+    let alpha = (color >> 24) as f64 / 255.0;
+    let channel = |shift: u32| ((color >> shift) & 0xff) as f64 / 255.0 * alpha;
+    wgpu::Color {
+        r: channel(0),
+        g: channel(8),
+        b: channel(16),
+        a: alpha,
+    }
+}
+
+// Drops the padding of the rows.
+fn unpad(padded_buffer: &[u8], layout: ImageLayout) -> Vec<u8> {
+    padded_buffer
+        .chunks(layout.padded_bytes_per_row as _)
+        .take(layout.height as _)
+        .flat_map(|row| &row[..layout.unpadded_bytes_per_row as _])
+        .copied()
+        .collect()
+}
+
+// Composites the premultiplied RGBA pixels into RGB onto R's color of the page
+// background with the alpha ignored, or onto white if it's transparent.
+fn composite(pixels: &[u8], background: i32) -> Vec<u8> {
+    let matte = if background.is_na() || (background as u32) >> 24 == 0 {
+        [255, 255, 255]
+    } else {
+        let color = background as u32;
+        [color & 0xff, (color >> 8) & 0xff, (color >> 16) & 0xff]
+    };
+
+    pixels
+        .chunks_exact(4)
+        .flat_map(|px| {
+            let transparency = 255 - px[3] as u32;
+            let channel =
+                |i: usize| (px[i] as u32 + (matte[i] * transparency + 127) / 255).min(255) as u8;
+            [channel(0), channel(1), channel(2)]
+        })
+        .collect()
+}
+
+// Converts the premultiplied RGBA pixels into the straight alpha in place.
+fn unpremultiply(pixels: &mut [u8]) {
+    for px in pixels.chunks_exact_mut(4) {
+        let alpha = px[3] as u32;
+        if alpha == 0 || alpha == 255 {
+            continue;
+        }
+        for c in &mut px[..3] {
+            *c = ((*c as u32 * 255 + alpha / 2) / alpha).min(255) as u8;
+        }
+    }
+}
+
+pub(crate) fn encode_image(
+    padded_buffer: &[u8],
+    filename: &Path,
+    layout: ImageLayout,
+    format: ImageFormat,
+    background: i32,
+) -> std::result::Result<(), String> {
+    let file = File::create(filename)
+        .map_err(|e| format!("Failed to create the output file {filename:?}: {e:?}"))?;
+    let mut writer = BufWriter::new(file);
+
+    let to_error = |e: image::ImageError| format!("Failed to write {filename:?}: {e}");
+
+    let (width, height) = (layout.width, layout.height);
+    let straight_pixels = || {
+        let mut pixels = unpad(padded_buffer, layout);
+        unpremultiply(&mut pixels);
+        pixels
+    };
+
+    match format {
+        // PNG can be written row by row, without copying the whole image
+        ImageFormat::Png => encode_png(padded_buffer, &mut writer, layout)
+            .map_err(|e| format!("Failed to write {filename:?}: {e:?}"))?,
+        ImageFormat::Jpeg { quality } => JpegEncoder::new_with_quality(&mut writer, quality)
+            .encode(
+                &composite(&unpad(padded_buffer, layout), background),
+                width,
+                height,
+                ColorType::Rgb8,
+            )
+            .map_err(to_error)?,
+        ImageFormat::WebP { lossless, quality } => {
+            let data = webp::Encoder::from_rgba(&straight_pixels(), width, height)
+                .encode_simple(lossless, quality as _)
+                .map_err(|e| format!("Failed to encode {filename:?}: {e:?}"))?;
+            writer
+                .write_all(&data)
+                .map_err(|e| format!("Failed to write {filename:?}: {e:?}"))?;
+        }
+        ImageFormat::Tiff => TiffEncoder::new(&mut writer)
+            .encode(&straight_pixels(), width, height, ColorType::Rgba8)
+            .map_err(to_error)?,
+        ImageFormat::Bmp => BmpEncoder::new(&mut writer)
+            .encode(
+                &composite(&unpad(padded_buffer, layout), background),
+                width,
+                height,
+                ColorType::Rgb8,
+            )
+            .map_err(to_error)?,
+    }
+
+    writer
+        .flush()
+        .map_err(|e| format!("Failed to write {filename:?}: {e:?}"))
+}
+
+fn encode_png<W: Write>(
+    padded_buffer: &[u8],
+    writer: W,
+    layout: ImageLayout,
+) -> std::result::Result<(), png::EncodingError> {
+    let mut png_encoder = png::Encoder::new(writer, layout.width, layout.height);
+
+    png_encoder.set_depth(png::BitDepth::Eight);
+    png_encoder.set_color(png::ColorType::Rgba);
+
+    let mut png_writer = png_encoder.write_header()?;
+    let mut stream_writer =
+        png_writer.stream_writer_with_size(layout.unpadded_bytes_per_row as _)?;
+
+    let mut row = vec![0; layout.unpadded_bytes_per_row as _];
+    for chunk in padded_buffer
+        .chunks(layout.padded_bytes_per_row as _)
+        .take(layout.height as _)
+    {
+        // while the buffer is padded, we only need the unpadded part
+        row.copy_from_slice(&chunk[..layout.unpadded_bytes_per_row as _]);
+        unpremultiply(&mut row);
+        stream_writer.write_all(&row)?;
+    }
+    stream_writer.finish()?;
+
+    png_writer.finish()
+}
+
+#[test]
+fn test_encode_png() {
+    // 2x2 pixels, padded to 16 bytes per row
+    let layout = ImageLayout {
+        width: 2,
+        height: 2,
+        unpadded_bytes_per_row: 8,
+        padded_bytes_per_row: 16,
+    };
+    let mut padded = vec![0xff_u8; 32];
+    padded[0..4].copy_from_slice(&[255, 0, 0, 255]);
+    // 50% red, premultiplied
+    padded[4..8].copy_from_slice(&[128, 0, 0, 128]);
+
+    let filename = std::env::temp_dir().join("wgpugd_test_encode_png.png");
+    encode_image(&padded, &filename, layout, ImageFormat::Png, i32::na()).unwrap();
+
+    let decoder = png::Decoder::new(File::open(&filename).unwrap());
+    let mut reader = decoder.read_info().unwrap();
+    let mut buf = vec![0; reader.output_buffer_size()];
+    reader.next_frame(&mut buf).unwrap();
+    std::fs::remove_file(&filename).unwrap();
+
+    assert_eq!(&buf[0..4], &[255, 0, 0, 255]);
+    assert_eq!(&buf[4..8], &[255, 0, 0, 128]);
+    assert_eq!(&buf[8..16], &[0xff; 8]);
+
+    // A directory that doesn't exist
+    let filename = std::env::temp_dir().join("wgpugd_no_such_dir/a.png");
+    assert!(encode_image(&padded, &filename, layout, ImageFormat::Png, i32::na()).is_err());
+}
+
+#[test]
+fn test_image_format() {
+    let format = |filename: &str| ImageFormat::from_path(Path::new(filename), 75, false).ok();
+
+    assert_eq!(format("Rplot%03d.png"), Some(ImageFormat::Png));
+    assert_eq!(format("a/b.JPG"), Some(ImageFormat::Jpeg { quality: 75 }));
+    assert_eq!(format("b.jpeg"), Some(ImageFormat::Jpeg { quality: 75 }));
+    assert_eq!(
+        format("c.webp"),
+        Some(ImageFormat::WebP {
+            lossless: false,
+            quality: 75
+        })
+    );
+    assert_eq!(format("d.tif"), Some(ImageFormat::Tiff));
+    assert_eq!(format("e.bmp"), Some(ImageFormat::Bmp));
+
+    assert_eq!(format("f.svg"), None);
+    assert_eq!(format("no_extension"), None);
+}
+
+#[test]
+fn test_composite() {
+    let white = 0xffffffff_u32 as i32;
+    let blue = 0xffff0000_u32 as i32;
+    // Opaque pixels are as they are
+    assert_eq!(composite(&[10, 20, 30, 255], blue), vec![10, 20, 30]);
+    // Transparent pixels are the background, or white if it's transparent
+    assert_eq!(composite(&[0, 0, 0, 0], blue), vec![0, 0, 255]);
+    assert_eq!(composite(&[0, 0, 0, 0], i32::na()), vec![255, 255, 255]);
+    assert_eq!(composite(&[0, 0, 0, 0], 0x00ff0000), vec![255, 255, 255]);
+    // 50% black (premultiplied) on the background
+    assert_eq!(composite(&[0, 0, 0, 128], white), vec![127, 127, 127]);
+    assert_eq!(composite(&[0, 0, 0, 128], blue), vec![0, 0, 127]);
+
+    let mut pixels = [10, 20, 30, 255, 64, 0, 32, 128, 0, 0, 0, 0];
+    unpremultiply(&mut pixels);
+    assert_eq!(pixels, [10, 20, 30, 255, 128, 0, 64, 128, 0, 0, 0, 0]);
+
+    let red = clear_color(0xff0000ff_u32 as i32);
+    assert_eq!((red.r, red.g, red.b, red.a), (1.0, 0.0, 0.0, 1.0));
+    // Premultiplied
+    let translucent_white = clear_color(0x80ffffff_u32 as i32);
+    assert_eq!(translucent_white.r, translucent_white.a);
+    assert_eq!(clear_color(i32::na()), wgpu::Color::TRANSPARENT);
+}
+
+#[test]
+fn test_encode_translucent_background() {
+    // 2x1 pixels of the translucent blue background (as cleared by
+    // `clear_color()`), and 50% black over it
+    let layout = ImageLayout {
+        width: 2,
+        height: 1,
+        unpadded_bytes_per_row: 8,
+        padded_bytes_per_row: 256,
+    };
+    let background = 0x80ff4020_u32 as i32;
+    let mut padded = vec![0_u8; 256];
+    padded[0..4].copy_from_slice(&[16, 32, 128, 128]);
+    padded[4..8].copy_from_slice(&[8, 16, 64, 192]);
+
+    for (extension, format, tolerance) in [
+        ("bmp", ImageFormat::Bmp, 0),
+        ("jpg", ImageFormat::Jpeg { quality: 100 }, 8),
+    ] {
+        let filename = std::env::temp_dir().join(format!("wgpugd_test_background.{extension}"));
+        encode_image(&padded, &filename, layout, format, background).unwrap();
+        let image = image::open(&filename).unwrap().to_rgb8();
+        std::fs::remove_file(&filename).unwrap();
+
+        // The background is opaque, and the translucent page on it is the
+        // same color (not mixed with white)
+        let expected: [[u8; 3]; 2] = [[32, 64, 255], [16, 32, 127]];
+        for (x, expected) in expected.iter().enumerate() {
+            let actual = image.get_pixel(x as _, 0).0;
+            for (a, e) in actual.iter().zip(expected) {
+                assert!(
+                    (*a as i32 - *e as i32).abs() <= tolerance,
+                    "{extension}: expected {expected:?}, got {actual:?}"
+                );
+            }
+        }
+    }
+}
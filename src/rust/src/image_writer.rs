@@ -0,0 +1,173 @@
+// Writing out a page (waiting for the GPU, mapping the output buffer, and
+// encoding the image) takes a while, so it's done on a worker thread while R
+// builds the next page. The output buffers are double-buffered so that the
+// next page can be rendered while the previous one is being written.
+
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+use crate::image_format::{encode_image, ImageFormat};
+
+const N_OUTPUT_BUFFERS: usize = 2;
+
+// The worker stops only when it panics, before the device is closed
+const WORKER_STOPPED: &str = "The image writer thread stopped unexpectedly";
+
+// The layout of the image in the output buffer. The rows are padded to
+// `wgpu::COPY_BYTES_PER_ROW_ALIGNMENT`, and only the unpadded part is written.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ImageLayout {
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+    pub(crate) unpadded_bytes_per_row: u32,
+    pub(crate) padded_bytes_per_row: u32,
+}
+
+struct Frame {
+    buffer: Arc<wgpu::Buffer>,
+    filename: PathBuf,
+    // R's color of the page background, for the formats without alpha
+    background: i32,
+}
+
+// The buffer is returned after the frame is written, with the result.
+type FrameResult = (Arc<wgpu::Buffer>, Result<(), String>);
+
+pub(crate) struct ImageWriter {
+    free_buffers: Vec<Arc<wgpu::Buffer>>,
+    frame_sender: Option<mpsc::Sender<Frame>>,
+    result_receiver: mpsc::Receiver<FrameResult>,
+    worker: Option<JoinHandle<()>>,
+    errors: Vec<String>,
+}
+
+impl ImageWriter {
+    pub(crate) fn new(
+        device: &'static wgpu::Device,
+        layout: ImageLayout,
+        format: ImageFormat,
+    ) -> Self {
+        let free_buffers = (0..N_OUTPUT_BUFFERS)
+            .map(|_| {
+                Arc::new(device.create_buffer(&wgpu::BufferDescriptor {
+                    label: Some("wgpugd output buffer"),
+                    size: (layout.padded_bytes_per_row * layout.height) as u64,
+                    usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+                    mapped_at_creation: false,
+                }))
+            })
+            .collect();
+
+        let (frame_sender, frame_receiver) = mpsc::channel::<Frame>();
+        let (result_sender, result_receiver) = mpsc::channel();
+
+        let worker = std::thread::spawn(move || {
+            for frame in frame_receiver {
+                let result = write_image(device, &frame, layout, format);
+                // If the device is already gone, there's no one to report to.
+                let _ = result_sender.send((frame.buffer, result));
+            }
+        });
+
+        Self {
+            free_buffers,
+            frame_sender: Some(frame_sender),
+            result_receiver,
+            worker: Some(worker),
+            errors: Vec::new(),
+        }
+    }
+
+    // Returns an output buffer to render the page into. If both are in use,
+    // this waits for the worker to finish writing the older one.
+    pub(crate) fn acquire_buffer(&mut self) -> Result<Arc<wgpu::Buffer>, String> {
+        if let Some(buffer) = self.free_buffers.pop() {
+            return Ok(buffer);
+        }
+
+        let (buffer, result) = self
+            .result_receiver
+            .recv()
+            .map_err(|_| WORKER_STOPPED.to_string())?;
+        if let Err(e) = result {
+            self.errors.push(e);
+        }
+        Ok(buffer)
+    }
+
+    // Writes the buffer into the file on the worker thread.
+    pub(crate) fn submit(
+        &mut self,
+        buffer: Arc<wgpu::Buffer>,
+        filename: PathBuf,
+        background: i32,
+    ) -> Result<(), String> {
+        if let Some(ref sender) = self.frame_sender {
+            sender
+                .send(Frame {
+                    buffer,
+                    filename,
+                    background,
+                })
+                .map_err(|_| WORKER_STOPPED.to_string())?;
+        }
+        Ok(())
+    }
+
+    // Waits for all the frames to be written, and returns the errors if any.
+    pub(crate) fn finish(&mut self) -> Result<(), String> {
+        // Closing the channel stops the worker.
+        drop(self.frame_sender.take());
+        if let Some(worker) = self.worker.take() {
+            if worker.join().is_err() {
+                self.errors
+                    .push("The image writer thread panicked".to_string());
+            }
+        }
+
+        for (_, result) in self.result_receiver.try_iter() {
+            if let Err(e) = result {
+                self.errors.push(e);
+            }
+        }
+
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(self.errors.drain(..).collect::<Vec<_>>().join("\n"))
+        }
+    }
+}
+
+// c.f. https://github.com/gfx-rs/wgpu/blob/312828f12f1a1497bc0387a72a5346ef911acad7/wgpu/examples/capture/main.rs#L119
+fn write_image(
+    device: &wgpu::Device,
+    frame: &Frame,
+    layout: ImageLayout,
+    format: ImageFormat,
+) -> Result<(), String> {
+    let buffer = &frame.buffer;
+    let buffer_slice = buffer.slice(..);
+    let buffer_future = buffer_slice.map_async(wgpu::MapMode::Read);
+
+    // Wait for the future resolves
+    device.poll(wgpu::Maintain::Wait);
+    pollster::block_on(buffer_future)
+        .map_err(|e| format!("Failed to read the rendered image: {e:?}"))?;
+
+    let result = encode_image(
+        &buffer_slice.get_mapped_range(),
+        &frame.filename,
+        layout,
+        format,
+        frame.background,
+    );
+
+    // With the current interface, we have to make sure all mapped views are
+    // dropped before we unmap the buffer.
+    buffer.unmap();
+
+    result
+}
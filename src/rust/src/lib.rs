@@ -1,13 +1,47 @@
+mod batch;
+mod buffer;
+mod clip;
+mod decimate;
 mod file;
+mod fxaa;
+mod glyph;
+mod gpu_context;
+#[cfg(test)]
+mod gpu_test;
 mod graphics_device;
+mod image_format;
+mod image_writer;
+mod marker;
+mod pixel_snap;
 mod render_pipeline;
+mod sdf_atlas;
+mod shader_cache;
+mod stats;
+mod stroke;
+mod supersample;
+mod systemfonts;
+mod tessellate;
 mod text;
 
+use crate::batch::BoundingBox;
+use crate::buffer::GrowableBuffer;
+use crate::clip::{ClipRect, CLIP_RECT_STRIDE};
 use crate::file::FilenameTemplate;
+use crate::fxaa::Fxaa;
+use crate::glyph::{GlyphMeshCache, GlyphRun};
+use crate::gpu_context::{gpu_context, GpuContext, RenderPipelines};
 use crate::graphics_device::WgpugdCommand;
-
-use std::io::Write;
-use std::{fs::File, path::PathBuf};
+use crate::image_format::{clear_color, ImageFormat};
+use crate::image_writer::{ImageLayout, ImageWriter};
+use crate::marker::SdfShape;
+use crate::render_pipeline::DEPTH_FORMAT;
+use crate::sdf_atlas::SdfGlyphAtlas;
+use crate::stroke::{StrokePoint, STROKE_POINT_SIZE};
+use crate::supersample::{DownsampleFilter, Supersampler};
+use crate::tessellate::{tessellate_jobs, TessellationJob, MAX_JOBS_PER_RENDER};
+use crate::text::{FontCache, TextMode};
+
+use std::path::PathBuf;
 
 use extendr_api::{
     graphics::{DeviceDescriptor, DeviceDriver},
@@ -15,8 +49,6 @@ use extendr_api::{
 };
 
 use lyon::lyon_tessellation::VertexBuffers;
-use render_pipeline::create_render_pipeline;
-use wgpu::util::DeviceExt;
 
 // For general shapes --------------------------------------------
 
@@ -25,11 +57,13 @@ use wgpu::util::DeviceExt;
 struct Vertex {
     position: [f32; 2],
     color: u32,
+    // To draw each pixel only once per shape (c.f. tessellate.rs)
+    depth: f32,
 }
 
 impl Vertex {
-    const ATTRIBS: [wgpu::VertexAttribute; 2] =
-        wgpu::vertex_attr_array![0 => Float32x2, 1 => Uint32];
+    const ATTRIBS: [wgpu::VertexAttribute; 3] =
+        wgpu::vertex_attr_array![0 => Float32x2, 1 => Uint32, 2 => Float32];
 
     fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
         wgpu::VertexBufferLayout {
@@ -43,7 +77,9 @@ impl Vertex {
 // For circles ----------------------------------------------------
 
 // For the sake of performance, we treat circle differently as they can be
-// simply represented by a SDF.
+// simply represented by a SDF. Each circle is drawn as the unit quad below
+// scaled to the bounding box of the circle. The same goes for the other simple
+// shapes that are used as markers (c.f. marker.rs).
 
 #[repr(C)]
 #[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
@@ -63,6 +99,15 @@ impl SDFVertex {
     }
 }
 
+// NA means not to draw, which is the same as fully transparent here.
+fn sdf_color(color: i32) -> u32 {
+    if color.is_na() {
+        0
+    } else {
+        unsafe { std::mem::transmute(color) }
+    }
+}
+
 // TODO: measure and set nicer default values
 const VERTEX_SIZE: usize = std::mem::size_of::<Vertex>();
 const VERTEX_BUFFER_INITIAL_SIZE: u64 = VERTEX_SIZE as u64 * 10000;
@@ -78,25 +123,202 @@ const RECT_VERTICES: &[SDFVertex] = &[
 ];
 const RECT_INDICES: &[u16] = &[0, 1, 2, 0, 2, 3];
 
+const SDF_INSTANCE_BUFFER_INITIAL_SIZE: u64 = std::mem::size_of::<SDFInstance>() as u64 * 1000;
+
+// For lines ----------------------------------------------------------
+
+// c.f. stroke.rs
+
+const STROKE_POINT_BUFFER_INITIAL_SIZE: u64 = STROKE_POINT_SIZE * 10000;
+
 #[repr(C)]
 #[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
 pub(crate) struct SDFInstance {
     center: [f32; 2],
-    radius: f32,
+    // The half of the width and the height (i.e. the radius for circles). For
+    // triangles, a negative height means the triangle points down. For cross
+    // and diagonal, these are along the diagonals.
+    half_size: [f32; 2],
+    // The radius of the corners of rounded rects, plus, cross, and diagonal
+    corner_radius: f32,
+    // The half of the thickness of the arms of plus and cross
+    arm_width: f32,
     stroke_width: f32,
     fill_color: u32,
     stroke_color: u32,
+    shape: u32,
 }
 
 impl SDFInstance {
-    const ATTRIBS: [wgpu::VertexAttribute; 5] = wgpu::vertex_attr_array![
+    const ATTRIBS: [wgpu::VertexAttribute; 8] = wgpu::vertex_attr_array![
         1 => Float32x2,
-        2 => Float32,
+        2 => Float32x2,
         3 => Float32,
+        4 => Float32,
+        5 => Float32,
+        6 => Uint32,
+        7 => Uint32,
+        8 => Uint32,
+    ];
+
+    pub(crate) fn new(
+        shape: SdfShape,
+        center: (f64, f64),
+        half_size: (f64, f64),
+        stroke_width: f32,
+        fill: i32,
+        color: i32,
+    ) -> Self {
+        Self {
+            center: [center.0 as _, center.1 as _],
+            half_size: [half_size.0 as _, half_size.1 as _],
+            corner_radius: 0.0,
+            arm_width: 0.0,
+            stroke_width,
+            fill_color: sdf_color(fill),
+            stroke_color: sdf_color(color),
+            shape: shape as _,
+        }
+    }
+
+    // The same extent as the quad in `sdf_shape.wgsl`
+    pub(crate) fn bounds(&self) -> BoundingBox {
+        let mut half_extent = [self.half_size[0].abs(), self.half_size[1].abs()];
+        if self.shape == SdfShape::Cross as u32 || self.shape == SdfShape::Diagonal as u32 {
+            let d = (half_extent[0] + half_extent[1] + self.arm_width)
+                * std::f32::consts::FRAC_1_SQRT_2;
+            half_extent = [d, d];
+        }
+        let extent_x = half_extent[0] + self.stroke_width * 0.5 + 1.0;
+        let extent_y = half_extent[1] + self.stroke_width * 0.5 + 1.0;
+        BoundingBox {
+            min: [self.center[0] - extent_x, self.center[1] - extent_y],
+            max: [self.center[0] + extent_x, self.center[1] + extent_y],
+        }
+    }
+
+    pub(crate) fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<Self>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Instance,
+            attributes: &Self::ATTRIBS,
+        }
+    }
+}
+
+// For glyphs ------------------------------------------------------
+
+// Each glyph is tessellated only once, and drawn as instances.
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub(crate) struct GlyphVertex {
+    pub(crate) position: [f32; 2],
+}
+
+impl GlyphVertex {
+    const ATTRIBS: [wgpu::VertexAttribute; 1] = wgpu::vertex_attr_array![0 => Float32x2];
+
+    fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<Self>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Vertex,
+            attributes: &Self::ATTRIBS,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub(crate) struct GlyphInstance {
+    // An affine transform
+    transform_x: [f32; 2],
+    transform_y: [f32; 2],
+    translation: [f32; 2],
+    color: u32,
+}
+
+impl GlyphInstance {
+    const ATTRIBS: [wgpu::VertexAttribute; 4] = wgpu::vertex_attr_array![
+        1 => Float32x2,
+        2 => Float32x2,
+        3 => Float32x2,
         4 => Uint32,
+    ];
+
+    pub(crate) fn new(transform: glam::Affine2, color: i32) -> Self {
+        Self {
+            transform_x: transform.matrix2.x_axis.into(),
+            transform_y: transform.matrix2.y_axis.into(),
+            translation: transform.translation.into(),
+            color: unsafe { std::mem::transmute(color) },
+        }
+    }
+
+    pub(crate) fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<Self>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Instance,
+            attributes: &Self::ATTRIBS,
+        }
+    }
+}
+
+const GLYPH_VERTEX_SIZE: usize = std::mem::size_of::<GlyphVertex>();
+const GLYPH_VERTEX_BUFFER_INITIAL_SIZE: u64 = GLYPH_VERTEX_SIZE as u64 * 10000;
+const GLYPH_INDEX_BUFFER_INITIAL_SIZE: u64 = INDEX_SIZE as u64 * 10000;
+const GLYPH_INSTANCE_BUFFER_INITIAL_SIZE: u64 =
+    std::mem::size_of::<GlyphInstance>() as u64 * 10000;
+
+// For glyphs in the SDF atlas -------------------------------------
+
+// Each glyph is a quad textured with the SDF in the atlas. The vertices are
+// generated in the shader, so there's no vertex buffer.
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub(crate) struct AtlasGlyphInstance {
+    // An affine transform that maps the unit square to the quad
+    transform_x: [f32; 2],
+    transform_y: [f32; 2],
+    translation: [f32; 2],
+    // The top-left corner and the size of the glyph in the atlas, in texels
+    texel_min: [f32; 2],
+    texel_size: [f32; 2],
+    color: u32,
+    // The bias on the distance to thicken the glyph
+    bias: f32,
+}
+
+impl AtlasGlyphInstance {
+    const ATTRIBS: [wgpu::VertexAttribute; 7] = wgpu::vertex_attr_array![
+        0 => Float32x2,
+        1 => Float32x2,
+        2 => Float32x2,
+        3 => Float32x2,
+        4 => Float32x2,
         5 => Uint32,
+        6 => Float32,
     ];
 
+    pub(crate) fn new(
+        transform: glam::Affine2,
+        texel_min: [f32; 2],
+        texel_size: [f32; 2],
+        color: i32,
+        bias: f32,
+    ) -> Self {
+        Self {
+            transform_x: transform.matrix2.x_axis.into(),
+            transform_y: transform.matrix2.y_axis.into(),
+            translation: transform.translation.into(),
+            texel_min,
+            texel_size,
+            color: unsafe { std::mem::transmute(color) },
+            bias,
+        }
+    }
+
     pub(crate) fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
         wgpu::VertexBufferLayout {
             array_stride: std::mem::size_of::<Self>() as wgpu::BufferAddress,
@@ -106,44 +328,144 @@ impl SDFInstance {
     }
 }
 
+const ATLAS_INSTANCE_BUFFER_INITIAL_SIZE: u64 =
+    std::mem::size_of::<AtlasGlyphInstance>() as u64 * 10000;
+
+// For the clip rects applied in the shaders (c.f. clip.rs)
+const CLIP_RECT_BUFFER_INITIAL_SIZE: u64 = CLIP_RECT_STRIDE * 16;
+
 #[repr(C)]
 #[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
 struct Globals {
     resolution: [f32; 2],
+    scale: f32,
+    // The uniform buffer needs to be aligned to 16 bytes
+    _padding: f32,
+}
+
+// The options specified on `wgpugd()`
+#[derive(Debug, Clone)]
+pub(crate) struct DeviceOptions {
+    pub(crate) text_mode: TextMode,
+    // If true, snap unrotated texts to the pixel grid, and thicken the stems
+    // of small texts.
+    pub(crate) snap_text: bool,
+    // If true, decimate the polylines that are denser than the pixel grid.
+    pub(crate) decimate: bool,
+    // Draw the commands so far once they take more bytes than this.
+    pub(crate) flush_threshold: usize,
+    // The sample count of MSAA; 1 (no MSAA), 2, 4, or 8
+    pub(crate) sample_count: u32,
+    // Render at this times the resolution, and downsample it with the filter
+    pub(crate) supersample: u32,
+    pub(crate) downsample_filter: DownsampleFilter,
+    // If true, smooth the edges by FXAA after rendering
+    pub(crate) fxaa: bool,
+    // The tessellation tolerance, in the pixels of the framebuffer
+    pub(crate) tolerance: f32,
+    // If true, draw the strokes thinner than a pixel as hairlines
+    pub(crate) hairline: bool,
+    // If true, snap axis-aligned lines and rects to the pixel grid
+    pub(crate) snap: bool,
+    // If true, clip at the exact bounds with anti-aliased edges when they
+    // don't fall on the pixel boundaries
+    pub(crate) exact_clip: bool,
+    // The quality of JPEG and lossy WebP, between 1 and 100
+    pub(crate) quality: u8,
+    // If true, compress WebP losslessly
+    pub(crate) lossless: bool,
+}
+
+impl DeviceOptions {
+    // The shapes are in points, and a point is `supersample` pixels of the
+    // framebuffer.
+    pub(crate) fn tolerance_in_points(&self) -> f32 {
+        self.tolerance / self.supersample as f32
+    }
+}
+
+// The pipeline that is set on the render pass
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PipelineKind {
+    Polygon,
+    Sdf,
+    Stroke,
+    Glyph,
+    Atlas,
 }
 
 #[allow(dead_code)]
 struct WgpuGraphicsDevice {
-    device: wgpu::Device,
-    queue: wgpu::Queue,
+    // The device, the queue, and the pipelines shared with the other devices
+    gpu: &'static GpuContext,
+    pipelines: &'static RenderPipelines,
 
     // For writing out a PNG
     texture: wgpu::Texture,
     texture_extent: wgpu::Extent3d,
-    output_buffer: wgpu::Buffer,
+    image_writer: ImageWriter,
 
     globals_bind_group: wgpu::BindGroup,
     globals_uniform_buffer: wgpu::Buffer,
+    // The first one is unbounded, and the rest are of the `SetClipping`
+    // commands with a clip rect, in order.
+    clip_rect_buffer: GrowableBuffer,
 
-    render_pipeline: wgpu::RenderPipeline,
-
-    vertex_buffer: wgpu::Buffer,
-    index_buffer: wgpu::Buffer,
-
-    sdf_vertex_buffer: wgpu::Buffer,
-    sdf_index_buffer: wgpu::Buffer,
-    sdf_render_pipeline: wgpu::RenderPipeline,
+    // The buffers are kept across pages, and recreated only when they get full.
+    vertex_buffer: GrowableBuffer,
+    index_buffer: GrowableBuffer,
 
     sdf_instances: Vec<SDFInstance>,
+    sdf_instance_buffer: GrowableBuffer,
 
-    geometry: VertexBuffers<Vertex, u32>,
+    stroke_points: Vec<StrokePoint>,
+    stroke_point_buffer: GrowableBuffer,
 
-    // For MSAA
-    multisampled_framebuffer: wgpu::TextureView,
+    // The paths are tessellated in render(), into `geometry`.
+    tessellation_jobs: Vec<TessellationJob>,
+    tessellation_bytes: usize,
+    geometry: VertexBuffers<Vertex, u32>,
 
-    // On clipping or instanced rendering layer, increment this layer id
-    current_command: Option<WgpugdCommand>,
+    // The glyph meshes are kept across pages, so the buffers are uploaded
+    // incrementally. The lengths are of the vertices and indices already
+    // uploaded.
+    glyph_meshes: GlyphMeshCache,
+    glyph_vertex_buffer: GrowableBuffer,
+    glyph_index_buffer: GrowableBuffer,
+    glyph_uploaded_vertices: usize,
+    glyph_uploaded_indices: usize,
+
+    glyph_instances: Vec<GlyphInstance>,
+    glyph_instance_buffer: GrowableBuffer,
+    glyph_runs: Vec<GlyphRun>,
+
+    options: DeviceOptions,
+
+    // The SDF atlas is also kept across pages. The texture is recreated when the
+    // atlas grows.
+    sdf_atlas: SdfGlyphAtlas,
+    atlas_texture: wgpu::Texture,
+    atlas_texture_height: u32,
+    atlas_bind_group: wgpu::BindGroup,
+
+    atlas_instances: Vec<AtlasGlyphInstance>,
+    atlas_instance_buffer: GrowableBuffer,
+
+    // For MSAA. None if the sample count is 1.
+    multisampled_framebuffer: Option<wgpu::TextureView>,
+    // For drawing each pixel only once per shape (c.f. tessellate.rs)
+    depth_buffer: wgpu::TextureView,
+    // For supersampling. None if the factor is 1.
+    supersampler: Option<Supersampler>,
+    // For FXAA. None if FXAA is disabled.
+    fxaa: Option<Fxaa>,
+
+    // The commands of the same kind are merged as far as the order of the
+    // overlapping primitives is kept (c.f. `batch.rs`).
     command_queue: Vec<WgpugdCommand>,
+    // True if a part of the current page is already drawn on the texture
+    // (c.f. `flush_if_needed()`).
+    page_flushed: bool,
 
     // width and height in point
     width: u32,
@@ -156,6 +478,11 @@ struct WgpuGraphicsDevice {
 
     filename: FilenameTemplate,
     cur_page: u32,
+    // The background color of the current page (i.e. `bg`), to clear the
+    // framebuffer with. This is set by `new_page()` before the page is drawn.
+    page_background: i32,
+
+    font_cache: FontCache,
 }
 
 impl WgpuGraphicsDevice {
@@ -163,28 +490,14 @@ impl WgpuGraphicsDevice {
         self.filename.filename(self.cur_page)
     }
 
-    async fn new(filename: &str, width: u32, height: u32) -> Self {
-        // Set envvar WGPU_BACKEND to specific backend (e.g., vulkan, dx12, metal, opengl)
-        let backend = wgpu::util::backend_bits_from_env().unwrap_or_else(wgpu::Backends::all);
-
-        // An `Instance` is a "context for all other wgpu objects"
-        let instance = wgpu::Instance::new(backend);
-
-        // An `Adapter` is a "handle to a physical graphics"
-        let adapter = instance
-            .request_adapter(&wgpu::RequestAdapterOptions {
-                power_preference: wgpu::PowerPreference::default(),
-                compatible_surface: None, // Currently no window so no surface
-                force_fallback_adapter: false,
-            })
-            .await
-            .unwrap();
+    fn new(filename: &str, width: u32, height: u32, options: DeviceOptions) -> Result<Self> {
+        let filename = FilenameTemplate::new(filename)?;
+        // All the pages have the same extension
+        let format =
+            ImageFormat::from_path(&filename.filename(1), options.quality, options.lossless)?;
 
-        // A `Device` is a "connection to a graphics device" and a `Queue` is a command queue.
-        let (device, queue) = adapter
-            .request_device(&Default::default(), None)
-            .await
-            .unwrap();
+        let gpu = gpu_context()?;
+        let device = &gpu.device;
 
         let texture_extent = wgpu::Extent3d {
             width,
@@ -219,14 +532,18 @@ impl WgpuGraphicsDevice {
         let padded_bytes_per_row_padding = (align - unpadded_bytes_per_row % align) % align;
         let padded_bytes_per_row = unpadded_bytes_per_row + padded_bytes_per_row_padding;
 
-        // Output buffer is where the texture is copied to, and then written out
-        // as a PNG image.
-        let output_buffer = device.create_buffer(&wgpu::BufferDescriptor {
-            label: Some("wgpugd output buffer"),
-            size: (padded_bytes_per_row * height) as u64,
-            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
-            mapped_at_creation: false,
-        });
+        // Output buffers are where the texture is copied to, and then written
+        // out as an image in the format of the filename's extension.
+        let image_writer = ImageWriter::new(
+            device,
+            ImageLayout {
+                width,
+                height,
+                unpadded_bytes_per_row,
+                padded_bytes_per_row,
+            },
+            format,
+        );
 
         let globals_uniform_buffer = device.create_buffer(&wgpu::BufferDescriptor {
             label: Some("wgpugd uniform buffer for globals"),
@@ -235,120 +552,207 @@ impl WgpuGraphicsDevice {
             mapped_at_creation: false,
         });
 
-        let globals_bind_group_layout =
-            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                label: Some("wgpugd globals bind group layout"),
-                entries: &[wgpu::BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Uniform,
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
-                }],
-            });
+        let clip_rect_buffer = GrowableBuffer::new(
+            device,
+            "wgpugd uniform buffer for clip rects",
+            wgpu::BufferUsages::UNIFORM,
+            CLIP_RECT_BUFFER_INITIAL_SIZE,
+        );
 
-        let globals_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            label: Some("wgpugd globals bind group"),
-            layout: &globals_bind_group_layout,
-            entries: &[wgpu::BindGroupEntry {
-                binding: 0,
-                resource: globals_uniform_buffer.as_entire_binding(),
-            }],
-        });
+        let globals_bind_group = create_globals_bind_group(
+            device,
+            &gpu.globals_bind_group_layout,
+            &globals_uniform_buffer,
+            &clip_rect_buffer.buffer,
+        );
+
+        // On supersampling, the framebuffers are larger than the output
+        let framebuffer_extent = wgpu::Extent3d {
+            width: width * options.supersample,
+            height: height * options.supersample,
+            depth_or_array_layers: 1,
+        };
+
+        // The supported sample counts and sizes depend on the GPU, so catch the
+        // errors instead of panicking.
+        device.push_error_scope(wgpu::ErrorFilter::Validation);
+
+        let multisampled_framebuffer = if options.sample_count > 1 {
+            let view = device
+                .create_texture(&wgpu::TextureDescriptor {
+                    label: Some("wgpugd multisampled framebuffer"),
+                    size: framebuffer_extent,
+                    mip_level_count: 1,
+                    sample_count: options.sample_count,
+                    dimension: wgpu::TextureDimension::D2,
+                    format: wgpu::TextureFormat::Rgba8Unorm,
+                    usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+                })
+                .create_view(&wgpu::TextureViewDescriptor::default());
+            Some(view)
+        } else {
+            None
+        };
 
-        let multisampled_framebuffer = device
+        let depth_buffer = device
             .create_texture(&wgpu::TextureDescriptor {
-                label: Some("wgpugd multisampled framebuffer"),
-                size: texture_extent,
+                label: Some("wgpugd depth buffer"),
+                size: framebuffer_extent,
                 mip_level_count: 1,
-                sample_count: 4,
+                sample_count: options.sample_count,
                 dimension: wgpu::TextureDimension::D2,
-                format: wgpu::TextureFormat::Rgba8Unorm,
+                format: DEPTH_FORMAT,
                 usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
             })
             .create_view(&wgpu::TextureViewDescriptor::default());
 
-        let render_pipeline = create_render_pipeline(
-            &device,
-            "wgpugd render pipeline layout",
-            "wgpugd render pipeline",
-            &[&globals_bind_group_layout],
-            &wgpu::include_wgsl!("shaders/shader.wgsl"),
-            &[Vertex::desc()],
-            4,
+        let supersampler = if options.supersample > 1 {
+            Some(Supersampler::new(
+                gpu,
+                framebuffer_extent,
+                options.supersample,
+                options.downsample_filter,
+            ))
+        } else {
+            None
+        };
+
+        // FXAA is applied after downsampling, so this is of the output size.
+        let fxaa = if options.fxaa {
+            Some(Fxaa::new(gpu, texture_extent))
+        } else {
+            None
+        };
+
+        if let Some(e) = pollster::block_on(device.pop_error_scope()) {
+            return Err(Error::Other(format!(
+                "antialias = {} and supersample = {} are not supported on this GPU: {e}",
+                options.sample_count, options.supersample
+            )));
+        }
+
+        let pipelines = gpu.render_pipelines(options.sample_count);
+
+        let vertex_buffer = GrowableBuffer::new(
+            device,
+            "wgpugd vertex buffer",
+            wgpu::BufferUsages::VERTEX,
+            VERTEX_BUFFER_INITIAL_SIZE,
         );
 
-        let vertex_buffer = device.create_buffer(&wgpu::BufferDescriptor {
-            label: Some("wgpugd vertex buffer"),
-            size: VERTEX_BUFFER_INITIAL_SIZE,
-            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
-            mapped_at_creation: false,
-        });
+        let index_buffer = GrowableBuffer::new(
+            device,
+            "wgpugd index buffer",
+            wgpu::BufferUsages::INDEX,
+            INDEX_BUFFER_INITIAL_SIZE,
+        );
 
-        let index_buffer = device.create_buffer(&wgpu::BufferDescriptor {
-            label: Some("wgpugd index buffer"),
-            size: INDEX_BUFFER_INITIAL_SIZE,
-            usage: wgpu::BufferUsages::INDEX | wgpu::BufferUsages::COPY_DST,
-            mapped_at_creation: false,
-        });
+        let sdf_instance_buffer = GrowableBuffer::new(
+            device,
+            "wgpugd instance buffer",
+            wgpu::BufferUsages::VERTEX,
+            SDF_INSTANCE_BUFFER_INITIAL_SIZE,
+        );
 
-        let sdf_vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("wgpugd vertex buffer"),
-            contents: bytemuck::cast_slice(RECT_VERTICES),
-            usage: wgpu::BufferUsages::VERTEX,
-        });
+        let stroke_point_buffer = GrowableBuffer::new(
+            device,
+            "wgpugd point buffer for lines",
+            wgpu::BufferUsages::VERTEX,
+            STROKE_POINT_BUFFER_INITIAL_SIZE,
+        );
 
-        let sdf_index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("wgpugd index buffer"),
-            contents: bytemuck::cast_slice(RECT_INDICES),
-            usage: wgpu::BufferUsages::INDEX,
-        });
+        let glyph_vertex_buffer = GrowableBuffer::new(
+            device,
+            "wgpugd vertex buffer for glyphs",
+            wgpu::BufferUsages::VERTEX,
+            GLYPH_VERTEX_BUFFER_INITIAL_SIZE,
+        );
 
-        let sdf_render_pipeline = create_render_pipeline(
-            &device,
-            "wgpugd render pipeline layout for SDF shapes",
-            "wgpugd render pipeline for SDF shapes",
-            &[&globals_bind_group_layout],
-            &wgpu::include_wgsl!("shaders/sdf_shape.wgsl"),
-            &[SDFVertex::desc(), SDFInstance::desc()],
-            // Technically, this doesn't need to be multisampled, as the SDF
-            // shapes are out of scope of MSAA anyway, but as we share the
-            // one renderpipline, the sample count must match the others.
-            4,
+        let glyph_index_buffer = GrowableBuffer::new(
+            device,
+            "wgpugd index buffer for glyphs",
+            wgpu::BufferUsages::INDEX,
+            GLYPH_INDEX_BUFFER_INITIAL_SIZE,
         );
 
-        let geometry: VertexBuffers<Vertex, u32> = VertexBuffers::new();
+        let glyph_instance_buffer = GrowableBuffer::new(
+            device,
+            "wgpugd instance buffer for glyphs",
+            wgpu::BufferUsages::VERTEX,
+            GLYPH_INSTANCE_BUFFER_INITIAL_SIZE,
+        );
 
-        Self {
+        let atlas_instance_buffer = GrowableBuffer::new(
             device,
-            queue,
+            "wgpugd instance buffer for the SDF atlas",
+            wgpu::BufferUsages::VERTEX,
+            ATLAS_INSTANCE_BUFFER_INITIAL_SIZE,
+        );
+
+        let sdf_atlas = SdfGlyphAtlas::new();
+
+        let atlas_texture = create_atlas_texture(device, sdf_atlas.width, sdf_atlas.height);
+
+        let atlas_bind_group = create_atlas_bind_group(
+            device,
+            &gpu.atlas_bind_group_layout,
+            &atlas_texture,
+            &gpu.atlas_sampler,
+        );
+
+        let geometry: VertexBuffers<Vertex, u32> = VertexBuffers::new();
+
+        Ok(Self {
+            gpu,
+            pipelines,
             texture,
             texture_extent,
-            output_buffer,
+            image_writer,
 
             globals_bind_group,
             globals_uniform_buffer,
-
-            render_pipeline,
+            clip_rect_buffer,
 
             vertex_buffer,
             index_buffer,
 
-            sdf_vertex_buffer,
-            sdf_index_buffer,
-            sdf_render_pipeline,
-
             sdf_instances: Vec::new(),
+            sdf_instance_buffer,
+
+            stroke_points: Vec::new(),
+            stroke_point_buffer,
 
+            tessellation_jobs: Vec::new(),
+            tessellation_bytes: 0,
             geometry,
 
+            glyph_meshes: GlyphMeshCache::new(options.tolerance_in_points()),
+            glyph_vertex_buffer,
+            glyph_index_buffer,
+            glyph_uploaded_vertices: 0,
+            glyph_uploaded_indices: 0,
+
+            glyph_instances: Vec::new(),
+            glyph_instance_buffer,
+            glyph_runs: Vec::new(),
+
+            options,
+
+            atlas_texture_height: sdf_atlas.height,
+            sdf_atlas,
+            atlas_texture,
+            atlas_bind_group,
+
+            atlas_instances: Vec::new(),
+            atlas_instance_buffer,
+
             multisampled_framebuffer,
+            depth_buffer,
+            supersampler,
+            fxaa,
 
-            current_command: None,
             command_queue: Vec::new(),
+            page_flushed: false,
 
             width,
             height,
@@ -356,45 +760,229 @@ impl WgpuGraphicsDevice {
             unpadded_bytes_per_row: unpadded_bytes_per_row as _,
             padded_bytes_per_row: padded_bytes_per_row as _,
 
-            filename: FilenameTemplate::new(filename).unwrap(),
+            filename,
             // The page number starts with 0, but newPage() will be immediately
             // called and this gets incremented to 1.
             cur_page: 0,
+            // Until the first page sets it
+            page_background: i32::na(),
+
+            font_cache: FontCache::default(),
+        })
+    }
+
+    // Upload the glyph meshes added since the last upload. If the buffer is
+    // full, recreate a larger one and upload all the meshes again.
+    fn upload_glyph_meshes(&mut self) {
+        let vertices = self.glyph_meshes.geometry.vertices.as_slice();
+        let indices = self.glyph_meshes.geometry.indices.as_slice();
+
+        let vertices_size = (GLYPH_VERTEX_SIZE * vertices.len()) as u64;
+        if self
+            .glyph_vertex_buffer
+            .reserve(&self.gpu.device, vertices_size)
+        {
+            self.glyph_uploaded_vertices = 0;
+        }
+
+        let indices_size = (INDEX_SIZE * indices.len()) as u64;
+        if self
+            .glyph_index_buffer
+            .reserve(&self.gpu.device, indices_size)
+        {
+            self.glyph_uploaded_indices = 0;
+        }
+
+        if self.glyph_uploaded_vertices < vertices.len() {
+            self.gpu.queue.write_buffer(
+                &self.glyph_vertex_buffer.buffer,
+                (GLYPH_VERTEX_SIZE * self.glyph_uploaded_vertices) as _,
+                bytemuck::cast_slice(&vertices[self.glyph_uploaded_vertices..]),
+            );
+            self.glyph_uploaded_vertices = vertices.len();
+        }
+
+        if self.glyph_uploaded_indices < indices.len() {
+            self.gpu.queue.write_buffer(
+                &self.glyph_index_buffer.buffer,
+                (INDEX_SIZE * self.glyph_uploaded_indices) as _,
+                bytemuck::cast_slice(&indices[self.glyph_uploaded_indices..]),
+            );
+            self.glyph_uploaded_indices = indices.len();
+        }
+    }
+
+    fn upload_clip_rects(&mut self) {
+        let clip_rects = self.command_queue.iter().filter_map(|cmd| match cmd {
+            WgpugdCommand::SetClipping { clip_rect, .. } => *clip_rect,
+            _ => None,
+        });
+
+        // Each clip rect is placed at the offset aligned for the dynamic offset
+        let mut data = vec![0_u8; CLIP_RECT_STRIDE as usize];
+        data[..std::mem::size_of::<ClipRect>()]
+            .copy_from_slice(bytemuck::bytes_of(&ClipRect::UNBOUNDED));
+        for clip_rect in clip_rects {
+            let offset = data.len();
+            data.resize(offset + CLIP_RECT_STRIDE as usize, 0);
+            data[offset..offset + std::mem::size_of::<ClipRect>()]
+                .copy_from_slice(bytemuck::bytes_of(&clip_rect));
+        }
+
+        // The bind group refers to the buffer, so it needs to be recreated
+        // along with the buffer.
+        if self.clip_rect_buffer.reserve(&self.gpu.device, data.len() as _) {
+            self.globals_bind_group = create_globals_bind_group(
+                &self.gpu.device,
+                &self.gpu.globals_bind_group_layout,
+                &self.globals_uniform_buffer,
+                &self.clip_rect_buffer.buffer,
+            );
         }
+        self.clip_rect_buffer
+            .write(&self.gpu.device, &self.gpu.queue, &data);
+    }
+
+    fn upload_sdf_atlas(&mut self) {
+        if !self.sdf_atlas.dirty {
+            return;
+        }
+
+        if self.atlas_texture_height != self.sdf_atlas.height {
+            self.atlas_texture = create_atlas_texture(
+                &self.gpu.device,
+                self.sdf_atlas.width,
+                self.sdf_atlas.height,
+            );
+            self.atlas_texture_height = self.sdf_atlas.height;
+            self.atlas_bind_group = create_atlas_bind_group(
+                &self.gpu.device,
+                &self.gpu.atlas_bind_group_layout,
+                &self.atlas_texture,
+                &self.gpu.atlas_sampler,
+            );
+        }
+
+        self.gpu.queue.write_texture(
+            self.atlas_texture.as_image_copy(),
+            &self.sdf_atlas.pixels,
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: std::num::NonZeroU32::new(self.sdf_atlas.width),
+                rows_per_image: None,
+            },
+            wgpu::Extent3d {
+                width: self.sdf_atlas.width,
+                height: self.sdf_atlas.height,
+                depth_or_array_layers: 1,
+            },
+        );
+
+        self.sdf_atlas.dirty = false;
     }
 
     fn render(&mut self) -> extendr_api::Result<()> {
-        // TODO: do this more nicely...
-        if let Some(ref cmd) = self.current_command {
-            self.command_queue.push(cmd.clone());
+        self.draw_commands(true)
+    }
+
+    // The bytes of the data waiting for render() on the CPU side
+    fn pending_bytes(&self) -> usize {
+        self.tessellation_bytes
+            + self.sdf_instances.len() * std::mem::size_of::<SDFInstance>()
+            + self.stroke_points.len() * std::mem::size_of::<StrokePoint>()
+            + self.glyph_instances.len() * std::mem::size_of::<GlyphInstance>()
+            + self.glyph_runs.len() * std::mem::size_of::<GlyphRun>()
+            + self.atlas_instances.len() * std::mem::size_of::<AtlasGlyphInstance>()
+    }
+
+    // If the commands so far take too much memory, draw them onto the texture
+    // and clear them, so that the memory is bounded however big the plot is.
+    // As this is called in the callbacks from R, an error is reported and the
+    // commands are discarded anyway.
+    fn flush_if_needed(&mut self) {
+        // The number of the polygons is also limited by the distinct depths.
+        if self.pending_bytes() < self.options.flush_threshold
+            && self.tessellation_jobs.len() < MAX_JOBS_PER_RENDER
+        {
+            return;
         }
 
-        // TODO: recreate the buffer when the data size is over the current buffer size.
-        self.queue.write_buffer(
-            &self.vertex_buffer,
-            0,
+        if let Err(e) = self.draw_commands(false) {
+            reprintln!("[ERROR] {e:?}");
+        }
+
+        // Keep the current clipping for the rest of the page
+        let clipping = self
+            .command_queue
+            .iter()
+            .rev()
+            .find(|cmd| matches!(cmd, WgpugdCommand::SetClipping { .. }))
+            .cloned();
+        self.clear_commands();
+        self.command_queue.extend(clipping);
+    }
+
+    fn clear_commands(&mut self) {
+        self.command_queue.clear();
+        self.tessellation_jobs.clear();
+        self.tessellation_bytes = 0;
+        self.sdf_instances.clear();
+        self.stroke_points.clear();
+        self.glyph_instances.clear();
+        self.glyph_runs.clear();
+        self.atlas_instances.clear();
+    }
+
+    // Draws the commands onto the texture. If `finish` is false, the page
+    // continues; the next call draws on top of this instead of clearing it.
+    fn draw_commands(&mut self, finish: bool) -> extendr_api::Result<()> {
+        let polygon_offsets = tessellate_jobs(&self.tessellation_jobs, &mut self.geometry);
+
+        let device = &self.gpu.device;
+        let queue = &self.gpu.queue;
+
+        self.vertex_buffer.write(
+            device,
+            queue,
             bytemuck::cast_slice(self.geometry.vertices.as_slice()),
         );
-        self.queue.write_buffer(
-            &self.index_buffer,
-            0,
+        self.index_buffer.write(
+            device,
+            queue,
             bytemuck::cast_slice(self.geometry.indices.as_slice()),
         );
+        self.sdf_instance_buffer.write(
+            device,
+            queue,
+            bytemuck::cast_slice(self.sdf_instances.as_slice()),
+        );
+        self.stroke_point_buffer.write(
+            device,
+            queue,
+            bytemuck::cast_slice(self.stroke_points.as_slice()),
+        );
+        self.glyph_instance_buffer.write(
+            device,
+            queue,
+            bytemuck::cast_slice(self.glyph_instances.as_slice()),
+        );
+        self.atlas_instance_buffer.write(
+            device,
+            queue,
+            bytemuck::cast_slice(self.atlas_instances.as_slice()),
+        );
 
-        let sdf_instance_buffer =
-            &self
-                .device
-                .create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                    label: Some("wgpugd instance buffer"),
-                    contents: bytemuck::cast_slice(self.sdf_instances.as_slice()),
-                    usage: wgpu::BufferUsages::VERTEX,
-                });
+        self.upload_glyph_meshes();
+        self.upload_sdf_atlas();
+        self.upload_clip_rects();
 
-        self.queue.write_buffer(
+        self.gpu.queue.write_buffer(
             &self.globals_uniform_buffer,
             0,
             bytemuck::cast_slice(&[Globals {
                 resolution: [self.width as _, self.height as _],
+                scale: self.options.supersample as _,
+                _padding: 0.0,
             }]),
         );
 
@@ -403,80 +991,242 @@ impl WgpuGraphicsDevice {
             .create_view(&wgpu::TextureViewDescriptor::default());
 
         let mut encoder = self
+            .gpu
             .device
             .create_command_encoder(&wgpu::CommandEncoderDescriptor {
                 label: Some("wgpugd render encoder"),
             });
 
+        // With FXAA, the image goes through the FXAA's framebuffer before the
+        // texture.
+        let fxaa_source_view = match self.fxaa {
+            Some(ref fxaa) => &fxaa.view,
+            None => &texture_view,
+        };
+
+        // On supersampling, render onto the larger framebuffer, and downsample
+        // it later.
+        let single_sampled_view = match self.supersampler {
+            Some(ref supersampler) => &supersampler.view,
+            None => fxaa_source_view,
+        };
+
+        // Without MSAA, render directly to the single-sampled view.
+        let (view, resolve_target) = match self.multisampled_framebuffer {
+            Some(ref multisampled_framebuffer) => {
+                (multisampled_framebuffer, Some(single_sampled_view))
+            }
+            None => (single_sampled_view, None),
+        };
+
         {
             let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
                 label: Some("wgpugd render pass"),
                 color_attachments: &[wgpu::RenderPassColorAttachment {
-                    view: &self.multisampled_framebuffer,
-                    resolve_target: Some(&texture_view),
+                    view,
+                    resolve_target,
                     ops: wgpu::Operations {
-                        // TODO: set the proper error from the value of gp->bg
-                        load: wgpu::LoadOp::Clear(wgpu::Color::WHITE),
+                        load: if self.page_flushed {
+                            wgpu::LoadOp::Load
+                        } else {
+                            wgpu::LoadOp::Clear(clear_color(self.page_background))
+                        },
                         // As described in the wgpu's example of MSAA, if the
                         // pre-resolved MSAA data is not used anywhere else, we
-                        // should set this to false to save memory.
-                        store: false,
+                        // should set this to false to save memory. But, it's
+                        // loaded by the next pass if the page continues.
+                        store: !finish || resolve_target.is_none(),
                     },
                 }],
-                depth_stencil_attachment: None,
+                // The depths of the shapes start over on every render, so
+                // the previous ones are not needed.
+                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                    view: &self.depth_buffer,
+                    depth_ops: Some(wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(0.0),
+                        store: false,
+                    }),
+                    stencil_ops: None,
+                }),
             });
 
             let mut begin_id_polygon = 0_u32;
             let mut last_id_polygon;
             let mut begin_id_sdf = 0_u32;
             let mut last_id_sdf;
+            let mut begin_id_stroke = 0_u32;
+            let mut last_id_stroke;
+            let mut begin_id_glyph = 0_u32;
+            let mut last_id_glyph;
+            let mut begin_id_atlas = 0_u32;
+            let mut last_id_atlas;
+
+            // The globals are shared by all the pipelines, so this needs to be
+            // set only once, except for the dynamic offset of the clip rect.
+            render_pass.set_bind_group(0, &self.globals_bind_group, &[0]);
+            stats::STATE_CHANGES.incr();
+            let mut clip_rect_index = 0;
+            let mut current_clip_offset = 0;
+
+            // The pipeline and the buffers are set only when the kind of the
+            // command changes (e.g. consecutive commands separated only by
+            // clipping don't need them again).
+            let mut current_pipeline: Option<PipelineKind> = None;
 
             for cmd in self.command_queue.iter() {
                 match cmd {
                     WgpugdCommand::DrawPolygon(cmd) => {
                         last_id_polygon = begin_id_polygon + cmd.count;
 
-                        render_pass.set_pipeline(&self.render_pipeline);
-                        render_pass.set_bind_group(0, &self.globals_bind_group, &[]);
-                        render_pass.set_vertex_buffer(
+                        if current_pipeline != Some(PipelineKind::Polygon) {
+                            render_pass.set_pipeline(&self.pipelines.render_pipeline);
+                            render_pass.set_vertex_buffer(
+                                0,
+                                self.vertex_buffer
+                                    .buffer
+                                    .slice(0..(VERTEX_SIZE * self.geometry.vertices.len()) as _),
+                            );
+                            render_pass.set_index_buffer(
+                                self.index_buffer
+                                    .buffer
+                                    .slice(0..(INDEX_SIZE * self.geometry.indices.len()) as _),
+                                wgpu::IndexFormat::Uint32,
+                            );
+                            stats::STATE_CHANGES.add(3);
+                            current_pipeline = Some(PipelineKind::Polygon);
+                        }
+
+                        // The ids are of the tessellation jobs; convert them to the indices.
+                        render_pass.draw_indexed(
+                            polygon_offsets[begin_id_polygon as usize]
+                                ..polygon_offsets[last_id_polygon as usize],
                             0,
-                            self.vertex_buffer
-                                .slice(0..(VERTEX_SIZE * self.geometry.vertices.len()) as _),
-                        );
-                        render_pass.set_index_buffer(
-                            self.index_buffer
-                                .slice(0..(INDEX_SIZE * self.geometry.indices.len()) as _),
-                            wgpu::IndexFormat::Uint32,
+                            0..1,
                         );
-                        render_pass.draw_indexed(begin_id_polygon..last_id_polygon, 0, 0..1);
+                        stats::DRAW_CALLS.incr();
 
                         begin_id_polygon = last_id_polygon;
                     }
                     WgpugdCommand::DrawSDF(cmd) => {
                         last_id_sdf = begin_id_sdf + cmd.count;
 
-                        render_pass.set_pipeline(&self.sdf_render_pipeline);
-                        render_pass.set_bind_group(0, &self.globals_bind_group, &[]);
-                        render_pass.set_vertex_buffer(0, self.sdf_vertex_buffer.slice(..));
-                        render_pass.set_vertex_buffer(1, sdf_instance_buffer.slice(..));
-                        render_pass.set_index_buffer(
-                            self.sdf_index_buffer.slice(..),
-                            wgpu::IndexFormat::Uint16,
-                        );
+                        if current_pipeline != Some(PipelineKind::Sdf) {
+                            render_pass.set_pipeline(&self.pipelines.sdf_render_pipeline);
+                            render_pass.set_vertex_buffer(0, self.gpu.sdf_vertex_buffer.slice(..));
+                            render_pass
+                                .set_vertex_buffer(1, self.sdf_instance_buffer.buffer.slice(..));
+                            render_pass.set_index_buffer(
+                                self.gpu.sdf_index_buffer.slice(..),
+                                wgpu::IndexFormat::Uint16,
+                            );
+                            stats::STATE_CHANGES.add(4);
+                            current_pipeline = Some(PipelineKind::Sdf);
+                        }
+
                         render_pass.draw_indexed(
                             0..RECT_INDICES.len() as _,
                             0,
                             begin_id_sdf..last_id_sdf,
                         );
+                        stats::DRAW_CALLS.incr();
 
                         begin_id_sdf = last_id_sdf;
                     }
+                    WgpugdCommand::DrawStroke(cmd) => {
+                        last_id_stroke = begin_id_stroke + cmd.count;
+
+                        if current_pipeline != Some(PipelineKind::Stroke) {
+                            render_pass.set_pipeline(&self.pipelines.stroke_render_pipeline);
+                            // The same buffer as the start and the end points
+                            // of the segments
+                            render_pass
+                                .set_vertex_buffer(0, self.stroke_point_buffer.buffer.slice(..));
+                            render_pass.set_vertex_buffer(
+                                1,
+                                self.stroke_point_buffer.buffer.slice(STROKE_POINT_SIZE..),
+                            );
+                            stats::STATE_CHANGES.add(3);
+                            current_pipeline = Some(PipelineKind::Stroke);
+                        }
+
+                        // The last point has no segment, so there's nothing to
+                        // draw with less than 2 points. The 6 vertices of the
+                        // quad are generated in the shader.
+                        if cmd.count >= 2 {
+                            render_pass.draw(0..6, begin_id_stroke..(last_id_stroke - 1));
+                            stats::DRAW_CALLS.incr();
+                        }
+
+                        begin_id_stroke = last_id_stroke;
+                    }
+                    WgpugdCommand::DrawGlyph(cmd) => {
+                        last_id_glyph = begin_id_glyph + cmd.count;
+
+                        if current_pipeline != Some(PipelineKind::Glyph) {
+                            render_pass.set_pipeline(&self.pipelines.glyph_render_pipeline);
+                            render_pass
+                                .set_vertex_buffer(0, self.glyph_vertex_buffer.buffer.slice(..));
+                            render_pass
+                                .set_vertex_buffer(1, self.glyph_instance_buffer.buffer.slice(..));
+                            render_pass.set_index_buffer(
+                                self.glyph_index_buffer.buffer.slice(..),
+                                wgpu::IndexFormat::Uint32,
+                            );
+                            stats::STATE_CHANGES.add(4);
+                            current_pipeline = Some(PipelineKind::Glyph);
+                        }
+
+                        // Each run is the instances of the same glyph
+                        for run in &self.glyph_runs[begin_id_glyph as usize..last_id_glyph as usize]
+                        {
+                            render_pass.draw_indexed(run.indices.clone(), 0, run.instances.clone());
+                            stats::DRAW_CALLS.incr();
+                        }
+
+                        begin_id_glyph = last_id_glyph;
+                    }
+                    WgpugdCommand::DrawAtlasGlyph(cmd) => {
+                        last_id_atlas = begin_id_atlas + cmd.count;
+
+                        if current_pipeline != Some(PipelineKind::Atlas) {
+                            render_pass.set_pipeline(&self.pipelines.atlas_render_pipeline);
+                            render_pass.set_bind_group(1, &self.atlas_bind_group, &[]);
+                            render_pass
+                                .set_vertex_buffer(0, self.atlas_instance_buffer.buffer.slice(..));
+                            stats::STATE_CHANGES.add(3);
+                            current_pipeline = Some(PipelineKind::Atlas);
+                        }
+
+                        // The 6 vertices of the quad are generated in the shader
+                        render_pass.draw(0..6, begin_id_atlas..last_id_atlas);
+                        stats::DRAW_CALLS.incr();
+
+                        begin_id_atlas = last_id_atlas;
+                    }
                     WgpugdCommand::SetClipping {
                         x,
                         y,
                         height,
                         width,
-                    } => render_pass.set_scissor_rect(*x, *y, *width, *height),
+                        clip_rect,
+                    } => {
+                        render_pass.set_scissor_rect(*x, *y, *width, *height);
+
+                        // The clip rects are in the same order as in
+                        // `upload_clip_rects()`
+                        let offset = match clip_rect {
+                            Some(_) => {
+                                clip_rect_index += 1;
+                                clip_rect_index * CLIP_RECT_STRIDE as u32
+                            }
+                            None => 0,
+                        };
+                        if offset != current_clip_offset {
+                            render_pass.set_bind_group(0, &self.globals_bind_group, &[offset]);
+                            stats::STATE_CHANGES.incr();
+                            current_clip_offset = offset;
+                        }
+                    }
                 }
             }
 
@@ -485,11 +1235,27 @@ impl WgpuGraphicsDevice {
 
             // Return the ownership. Otherwise the next operation on encoder would fail
             drop(render_pass);
+        }
 
+        if let (true, Some(supersampler)) = (finish, &self.supersampler) {
+            supersampler.downsample(self.gpu, &mut encoder, fxaa_source_view);
+        }
+
+        if let (true, Some(fxaa)) = (finish, &self.fxaa) {
+            fxaa.apply(self.gpu, &mut encoder, &texture_view);
+        }
+
+        let output_buffer = if finish {
+            Some(self.image_writer.acquire_buffer().map_err(Error::Other)?)
+        } else {
+            None
+        };
+
+        if let Some(ref output_buffer) = output_buffer {
             encoder.copy_texture_to_buffer(
                 self.texture.as_image_copy(),
                 wgpu::ImageCopyBuffer {
-                    buffer: &self.output_buffer,
+                    buffer: output_buffer,
                     layout: wgpu::ImageDataLayout {
                         offset: 0,
                         bytes_per_row: Some(
@@ -504,88 +1270,321 @@ impl WgpuGraphicsDevice {
             );
         }
 
-        self.queue.submit(Some(encoder.finish()));
-
-        Ok(())
-    }
-
-    // c.f. https://github.com/gfx-rs/wgpu/blob/312828f12f1a1497bc0387a72a5346ef911acad7/wgpu/examples/capture/main.rs#L119
-    async fn write_png(&mut self) {
-        let file = match File::create(self.filename()) {
-            Ok(f) => f,
-            Err(e) => {
-                reprintln!("Failed to create the output file: {e:?}");
-                return;
-            }
-        };
+        self.gpu.queue.submit(Some(encoder.finish()));
 
-        let buffer_slice = self.output_buffer.slice(..);
-        let buffer_future = buffer_slice.map_async(wgpu::MapMode::Read);
-
-        // Wait for the future resolves
-        self.device.poll(wgpu::Maintain::Wait);
-
-        if let Ok(()) = buffer_future.await {
-            let padded_buffer = buffer_slice.get_mapped_range();
-
-            let mut png_encoder = png::Encoder::new(file, self.width, self.height);
+        // The image is written on the worker thread
+        if let Some(output_buffer) = output_buffer {
+            self.image_writer
+                .submit(output_buffer, self.filename(), self.page_background)
+                .map_err(Error::Other)?;
+        }
 
-            png_encoder.set_depth(png::BitDepth::Eight);
-            png_encoder.set_color(png::ColorType::Rgba);
+        self.page_flushed = !finish;
 
-            // TODO: handle results nicely
-            let mut png_writer = png_encoder
-                .write_header()
-                .unwrap()
-                .into_stream_writer_with_size(self.unpadded_bytes_per_row as _)
-                .unwrap();
+        Ok(())
+    }
+}
 
-            for chunk in padded_buffer.chunks(self.padded_bytes_per_row as _) {
-                png_writer
-                    // while the buffer is padded, we only need the unpadded part
-                    .write_all(&chunk[..self.unpadded_bytes_per_row as _])
-                    .unwrap();
-            }
-            png_writer.finish().unwrap();
+fn create_atlas_texture(device: &wgpu::Device, width: u32, height: u32) -> wgpu::Texture {
+    device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("wgpugd texture for the SDF atlas"),
+        size: wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        // The distance is stored in a single channel
+        format: wgpu::TextureFormat::R8Unorm,
+        usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+    })
+}
 
-            // With the current interface, we have to make sure all mapped views are
-            // dropped before we unmap the buffer.
-            drop(padded_buffer);
+fn create_globals_bind_group(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    globals_uniform_buffer: &wgpu::Buffer,
+    clip_rect_buffer: &wgpu::Buffer,
+) -> wgpu::BindGroup {
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: Some("wgpugd globals bind group"),
+        layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: globals_uniform_buffer.as_entire_binding(),
+            },
+            // Only one clip rect is visible at a time
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                    buffer: clip_rect_buffer,
+                    offset: 0,
+                    size: wgpu::BufferSize::new(std::mem::size_of::<ClipRect>() as _),
+                }),
+            },
+        ],
+    })
+}
 
-            self.output_buffer.unmap();
-        }
-    }
+fn create_atlas_bind_group(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    texture: &wgpu::Texture,
+    sampler: &wgpu::Sampler,
+) -> wgpu::BindGroup {
+    let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: Some("wgpugd bind group for the SDF atlas"),
+        layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::TextureView(&view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: wgpu::BindingResource::Sampler(sampler),
+            },
+        ],
+    })
 }
 
 /// A WebGPU Graphics Device for R
 ///
-/// @param filename
+/// @param filename The output filename. `%d` (e.g. `%03d`) is replaced with the
+///   page number. The format is chosen by the extension; `.png`, `.jpg` (or
+///   `.jpeg`), `.webp`, `.tif` (or `.tiff`), and `.bmp` are supported. JPEG and
+///   BMP have no transparency, so a translucent `bg` is composited onto white.
 /// @param width  Device width in inch.
 /// @param height Device width in inch.
+/// @param text_mode How to render texts. `"outline"` tessellates the outlines
+///   of the glyphs, and `"atlas"` renders the glyphs from a texture atlas of
+///   signed distance fields, which looks crisper especially on small texts.
+/// @param snap_text If `TRUE`, place unrotated texts on the pixel grid (the
+///   baseline on the pixel boundary, and the x-height scaled to a whole number
+///   of pixels), and thicken the stems of texts smaller than 10 pixels a bit.
+/// @param decimate If `TRUE`, drop the points of polylines that don't change
+///   the pixels their center lines pass through (only the first, the last, the
+///   minimum, and the maximum points in each column of pixels are kept). This
+///   makes long time series much faster to draw. The anti-aliased edges look
+///   lighter than without decimation, where the overlapping segments darken
+///   them, but the decimated lines never get noticeably darker.
+/// @param flush_threshold The memory (in MB) for the shapes to draw. If a
+///   page takes more than this, the shapes so far are drawn and discarded, so
+///   that a huge plot doesn't exhaust the memory.
+/// @param antialias The number of samples per pixel for MSAA; 1 (no
+///   anti-aliasing), 2, 4, or 8. Not all GPUs support 2 and 8, and an error
+///   lists the supported ones.
+/// @param supersample Render at this times the resolution (1 to 4), and
+///   downsample the result into the output size. This smooths what MSAA
+///   doesn't, e.g. the inside of thin lines and the texts, at the cost of
+///   `supersample^2` times the pixels to draw.
+/// @param downsample The filter to downsample the supersampled image with;
+///   `"lanczos"` is sharper, and `"box"` is faster.
+/// @param fxaa If `TRUE`, smooth the edges by FXAA after rendering instead of
+///   MSAA (`antialias` is ignored). This is much cheaper than MSAA on software
+///   renderers like lavapipe, though thin lines look a bit blurrier.
+/// @param tolerance The maximum distance (in pixels) between the curves (e.g.
+///   the outlines of the glyphs and the round joins) and the polygons that
+///   approximate them. A larger value is faster but less accurate.
+/// @param hairline If `TRUE`, draw the lines thinner than a pixel (e.g.
+///   gridlines of `lwd = 0.5`) 1 pixel wide with the alpha reduced by the
+///   ratio, instead of faint and uneven sub-pixel lines.
+/// @param snap If `TRUE`, move axis-aligned lines and the edges of rects to
+///   the pixel grid so that they look crisp; a line of an odd width in pixels
+///   is centered on the pixel centers, and an even one on the pixel boundaries.
+/// @param exact_clip If `TRUE`, clip at the exact bounds with anti-aliased
+///   edges when they don't fall on the pixel boundaries (e.g. the panels of
+///   facets), instead of rounding them to whole pixels.
+/// @param quality The quality of JPEG and lossy WebP, between 1 and 100.
+/// @param lossless If `TRUE`, compress WebP losslessly. `quality` is ignored
+///   then.
+///
+/// @section Quality:
+///
+/// For quick previews, `antialias = 1` is the fastest. For print,
+/// `antialias = 8, supersample = 2` gives the smoothest result. Without a GPU,
+/// `fxaa = TRUE` is a good compromise.
+///
+/// @section Fonts:
+///
+/// In addition to a family name, `fontfamily` can specify the weight, the width,
+/// and the coordinates of the axes of a variable font after colons, e.g.
+/// `"Roboto Flex:wght=550:wdth=75:opsz=14"`. The weight can be a number
+/// between 1 and 1000 or a name like `semibold`, and the width can be a
+/// percentage or a name like `condensed`.
+///
+/// If the systemfonts package is installed, font families are resolved through
+/// it, so the fonts registered by `systemfonts::register_font()` are available
+/// as well as with other devices like ragg.
 /// @export
 #[extendr]
 fn wgpugd(
     #[default = "'Rplot%03d.png'"] filename: &str,
     #[default = "7"] width: i32,
     #[default = "7"] height: i32,
-) {
+    #[default = "'outline'"] text_mode: &str,
+    #[default = "FALSE"] snap_text: bool,
+    #[default = "FALSE"] decimate: bool,
+    #[default = "256"] flush_threshold: i32,
+    #[default = "4"] antialias: i32,
+    #[default = "1"] supersample: i32,
+    #[default = "'lanczos'"] downsample: &str,
+    #[default = "FALSE"] fxaa: bool,
+    #[default = "0.1"] tolerance: f64,
+    #[default = "FALSE"] hairline: bool,
+    #[default = "FALSE"] snap: bool,
+    #[default = "FALSE"] exact_clip: bool,
+    #[default = "75"] quality: i32,
+    #[default = "FALSE"] lossless: bool,
+) -> Result<()> {
+    let text_mode = match text_mode {
+        "outline" => TextMode::Outline,
+        "atlas" => TextMode::Atlas,
+        _ => {
+            return Err(Error::Other(format!(
+                "text_mode must be either 'outline' or 'atlas', not '{text_mode}'"
+            )))
+        }
+    };
+
+    if flush_threshold <= 0 {
+        return Err(Error::Other(format!(
+            "flush_threshold must be positive, not {flush_threshold}"
+        )));
+    }
+
+    // FXAA replaces MSAA; applying both would only blur the edges MSAA has
+    // already smoothed.
+    let antialias = if fxaa { 1 } else { antialias };
+
+    let sample_counts = &gpu_context()?.sample_counts;
+    if !sample_counts.contains(&(antialias as u32)) {
+        let supported: Vec<String> = sample_counts.iter().map(|n| n.to_string()).collect();
+        return Err(Error::Other(format!(
+            "antialias must be one of {} on this GPU, not {antialias}",
+            supported.join(", ")
+        )));
+    }
+
+    if !(1..=4).contains(&supersample) {
+        return Err(Error::Other(format!(
+            "supersample must be between 1 and 4, not {supersample}"
+        )));
+    }
+
+    let downsample_filter = match downsample {
+        "box" => DownsampleFilter::Box,
+        "lanczos" => DownsampleFilter::Lanczos,
+        _ => {
+            return Err(Error::Other(format!(
+                "downsample must be either 'box' or 'lanczos', not '{downsample}'"
+            )))
+        }
+    };
+
+    if !tolerance.is_finite() || tolerance <= 0.0 {
+        return Err(Error::Other(format!(
+            "tolerance must be a positive finite number, not {tolerance}"
+        )));
+    }
+
+    if !(1..=100).contains(&quality) {
+        return Err(Error::Other(format!(
+            "quality must be between 1 and 100, not {quality}"
+        )));
+    }
+
+    systemfonts::init_system_fonts();
+
     // Typically, 72 points per inch
     let width_pt = width * 72;
     let height_pt = height * 72;
 
-    let device_driver = pollster::block_on(WgpuGraphicsDevice::new(
+    let device_driver = WgpuGraphicsDevice::new(
         filename,
         width_pt as _,
         height_pt as _,
-    ));
+        DeviceOptions {
+            text_mode,
+            snap_text,
+            decimate,
+            flush_threshold: flush_threshold as usize * 1024 * 1024,
+            sample_count: antialias as _,
+            supersample: supersample as _,
+            downsample_filter,
+            fxaa,
+            tolerance: tolerance as _,
+            hairline,
+            snap,
+            exact_clip,
+            quality: quality as _,
+            lossless,
+        },
+    )?;
 
     let device_descriptor =
         DeviceDescriptor::new().device_size(0.0, width_pt as _, 0.0, height_pt as _);
 
     device_driver.create_device::<WgpuGraphicsDevice>(device_descriptor, "wgpugd");
+
+    Ok(())
+}
+
+/// Internal counters for benchmarking
+///
+/// @param reset If `TRUE`, reset the counters to zero after reading them.
+/// @noRd
+#[extendr]
+fn wgpugd_stats(#[default = "FALSE"] reset: bool) -> Robj {
+    let res = list!(
+        char_metric_calls = stats::CHAR_METRIC_CALLS.get() as f64,
+        font_queries = stats::FONT_QUERIES.get() as f64,
+        glyph_metric_lookups = stats::GLYPH_METRIC_LOOKUPS.get() as f64,
+        draw_calls = stats::DRAW_CALLS.get() as f64,
+        state_changes = stats::STATE_CHANGES.get() as f64,
+        buffer_allocations = stats::BUFFER_ALLOCATIONS.get() as f64
+    );
+
+    if reset {
+        stats::reset_all();
+    }
+
+    res.into()
+}
+
+/// Clear the shader cache
+///
+/// On Vulkan, the shaders translated into SPIR-V are cached under
+/// `tools::R_user_dir("wgpugd", which = "cache")` so that the following R
+/// sessions can skip the translation. This removes the cache. The devices
+/// already opened in this session are not affected.
+///
+/// @export
+#[extendr]
+fn wgpugd_clear_cache() -> Result<()> {
+    let dir = match shader_cache::cache_dir() {
+        Some(dir) => dir,
+        None => return Ok(()),
+    };
+
+    match std::fs::remove_dir_all(&dir) {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(Error::Other(format!(
+            "Failed to remove {}: {e}",
+            dir.to_string_lossy()
+        ))),
+    }
 }
 
 extendr_module! {
     mod wgpugd;
     fn wgpugd;
+    fn wgpugd_stats;
+    fn wgpugd_clear_cache;
 }
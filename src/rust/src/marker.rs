@@ -0,0 +1,157 @@
+// R draws the markers (`pch`) with the ordinary primitives; e.g. squares via
+// rect(), triangles and diamonds via polygon(), plus via two line()s, and cross
+// via two diagonal line()s. As there can be a lot of markers (e.g. a scatter
+// plot), they are detected here and drawn as instances of SDF shapes like
+// circles, instead of tessellating one by one. The other markers are
+// combinations of these (e.g. pch 8 is a cross and a plus).
+
+// The kinds of SDF shapes. These must match with the ones in `sdf_shape.wgsl`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u32)]
+pub(crate) enum SdfShape {
+    Circle = 0,
+    // No R's primitive corresponds to an ellipse, so this is not used yet.
+    #[allow(dead_code)]
+    Ellipse = 1,
+    // A rect with mitre joins
+    Rect = 2,
+    // A rect with round joins (and rounded corners if `corner_radius` > 0)
+    RoundedRect = 3,
+    Triangle = 4,
+    Diamond = 5,
+    Plus = 6,
+    Cross = 7,
+    // A rect rotated by 45 degrees, i.e. a diagonal line. The half size is
+    // along the diagonals.
+    Diagonal = 8,
+}
+
+const EPSILON: f64 = 1e-3;
+
+fn approx_eq(a: f64, b: f64) -> bool {
+    (a - b).abs() < EPSILON
+}
+
+// The center and the half of the size of a shape
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct ShapeBox {
+    pub(crate) center: (f64, f64),
+    pub(crate) half_size: (f64, f64),
+}
+
+// Detects an isosceles triangle whose base is horizontal (e.g. pch 2 and 6).
+// The height is negative if the triangle points down.
+pub(crate) fn detect_triangle(points: &[(f64, f64)]) -> Option<ShapeBox> {
+    if points.len() != 3 {
+        return None;
+    }
+
+    for i in 0..3 {
+        let apex = points[i];
+        let b1 = points[(i + 1) % 3];
+        let b2 = points[(i + 2) % 3];
+
+        if !approx_eq(b1.1, b2.1) || approx_eq(b1.0, b2.0) {
+            continue;
+        }
+
+        let base_center_x = (b1.0 + b2.0) / 2.0;
+        if !approx_eq(apex.0, base_center_x) || approx_eq(apex.1, b1.1) {
+            continue;
+        }
+
+        return Some(ShapeBox {
+            center: (base_center_x, (apex.1 + b1.1) / 2.0),
+            half_size: ((b1.0 - b2.0).abs() / 2.0, (apex.1 - b1.1) / 2.0),
+        });
+    }
+
+    None
+}
+
+// Detects a diamond whose diagonals are horizontal and vertical (e.g. pch 5).
+pub(crate) fn detect_diamond(points: &[(f64, f64)]) -> Option<ShapeBox> {
+    if points.len() != 4 {
+        return None;
+    }
+
+    // The opposite vertices are either (0, 2) and (1, 3), in whichever order
+    // the polygon goes around.
+    let (h, v) = if approx_eq(points[0].1, points[2].1) {
+        ((points[0], points[2]), (points[1], points[3]))
+    } else {
+        ((points[1], points[3]), (points[0], points[2]))
+    };
+
+    let center = ((h.0 .0 + h.1 .0) / 2.0, h.0 .1);
+    let half_size = ((h.0 .0 - h.1 .0).abs() / 2.0, (v.0 .1 - v.1 .1).abs() / 2.0);
+
+    let is_diamond = approx_eq(h.0 .1, h.1 .1)
+        && approx_eq(v.0 .0, center.0)
+        && approx_eq(v.1 .0, center.0)
+        && approx_eq((v.0 .1 + v.1 .1) / 2.0, center.1)
+        && half_size.0 > EPSILON
+        && half_size.1 > EPSILON;
+
+    if is_diamond {
+        Some(ShapeBox { center, half_size })
+    } else {
+        None
+    }
+}
+
+#[test]
+fn test_detect_triangle() {
+    // pch 2
+    let up = [(1.0, 3.0), (2.0, 1.0), (0.0, 1.0)];
+    assert_eq!(
+        detect_triangle(&up),
+        Some(ShapeBox {
+            center: (1.0, 2.0),
+            half_size: (1.0, 1.0)
+        })
+    );
+
+    // pch 6, starting from a different vertex
+    let down = [(2.0, 3.0), (0.0, 3.0), (1.0, 1.0)];
+    assert_eq!(
+        detect_triangle(&down),
+        Some(ShapeBox {
+            center: (1.0, 2.0),
+            half_size: (1.0, -1.0)
+        })
+    );
+
+    // Not isosceles
+    assert_eq!(detect_triangle(&[(0.5, 3.0), (2.0, 1.0), (0.0, 1.0)]), None);
+    // The base is not horizontal
+    assert_eq!(detect_triangle(&[(1.0, 3.0), (2.0, 1.5), (0.0, 1.0)]), None);
+}
+
+#[test]
+fn test_detect_diamond() {
+    // pch 5
+    let diamond = [(0.0, 1.0), (1.0, 2.0), (2.0, 1.0), (1.0, 0.0)];
+    assert_eq!(
+        detect_diamond(&diamond),
+        Some(ShapeBox {
+            center: (1.0, 1.0),
+            half_size: (1.0, 1.0)
+        })
+    );
+
+    let diamond = [(1.0, 2.0), (3.0, 1.0), (1.0, 0.0), (-1.0, 1.0)];
+    assert_eq!(
+        detect_diamond(&diamond),
+        Some(ShapeBox {
+            center: (1.0, 1.0),
+            half_size: (2.0, 1.0)
+        })
+    );
+
+    // A square
+    assert_eq!(
+        detect_diamond(&[(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0)]),
+        None
+    );
+}
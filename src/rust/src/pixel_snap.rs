@@ -0,0 +1,85 @@
+// Thin lines and the edges of axis-aligned shapes (e.g. gridlines and bars)
+// look blurry and uneven if they fall between the pixels. Like the Cairo-based
+// devices, they are moved to the pixel grid, and the strokes thinner than a
+// pixel are drawn as hairlines, 1 pixel wide with the alpha reduced instead.
+//
+// The pixels here are the ones of the output, i.e. points. On supersampling,
+// the framebuffer has finer pixels, but they are downsampled into the output
+// pixels anyway.
+
+use extendr_api::prelude::*;
+
+// Returns the width and the color of the hairline if the stroke is thinner
+// than a pixel. A zero width is left as it is.
+pub(crate) fn hairline(line_width: f32, color: i32) -> (f32, i32) {
+    if line_width <= 0.0 || line_width >= 1.0 || color.is_na() {
+        return (line_width, color);
+    }
+
+    (1.0, scale_alpha(color, line_width))
+}
+
+// R's color is in the order of RGBA, i.e. the alpha is the most significant
+// byte.
+fn scale_alpha(color: i32, ratio: f32) -> i32 {
+    let color = color as u32;
+    let alpha = ((color >> 24) as f32 * ratio).round() as u32;
+    ((color & 0x00ff_ffff) | (alpha << 24)) as i32
+}
+
+// Snaps the center of a stroke so that its edges fall on the pixel boundaries;
+// a stroke of an odd number of pixels is centered on a pixel center, and an
+// even one on a pixel boundary.
+pub(crate) fn snap_stroke_center(x: f64, line_width: f32) -> f64 {
+    let width = (line_width as f64).round().max(1.0);
+    // 0.5 if the width is odd, 0 if even
+    let offset = (width / 2.0).fract();
+    (x - offset).round() + offset
+}
+
+// Snaps the edges of a span (e.g. the left and the right of a rect). If the
+// edges have a stroke, they are snapped as the stroke's centers, otherwise to
+// the pixel boundaries. A span that would collapse is left as it is.
+pub(crate) fn snap_span(a: f64, b: f64, line_width: Option<f32>) -> (f64, f64) {
+    let snap = |x: f64| match line_width {
+        Some(line_width) => snap_stroke_center(x, line_width),
+        None => x.round(),
+    };
+
+    let (a_snapped, b_snapped) = (snap(a), snap(b));
+    if a_snapped == b_snapped && a != b {
+        (a, b)
+    } else {
+        (a_snapped, b_snapped)
+    }
+}
+
+#[test]
+fn test_hairline() {
+    let black = 0xff000000_u32 as i32;
+
+    // Thick enough
+    assert_eq!(hairline(1.5, black), (1.5, black));
+    // 0.375pt (lwd = 0.5) is 1 pixel with 3/8 of the alpha
+    assert_eq!(hairline(0.375, black), (1.0, 0x60000000_u32 as i32));
+    assert_eq!(hairline(0.75, black), (1.0, 0xbf000000_u32 as i32));
+
+    assert_eq!(hairline(0.0, black), (0.0, black));
+    assert_eq!(hairline(0.5, i32::na()), (0.5, i32::na()));
+}
+
+#[test]
+fn test_snap() {
+    // 1px on the pixel center, 2px on the boundary
+    assert_eq!(snap_stroke_center(10.2, 1.0), 10.5);
+    assert_eq!(snap_stroke_center(10.2, 2.0), 10.0);
+    assert_eq!(snap_stroke_center(10.8, 2.0), 11.0);
+    // Hairlines are 1px
+    assert_eq!(snap_stroke_center(10.9, 0.3), 10.5);
+    assert_eq!(snap_stroke_center(10.2, 0.5), 10.5);
+
+    assert_eq!(snap_span(1.3, 20.6, None), (1.0, 21.0));
+    assert_eq!(snap_span(1.3, 20.6, Some(1.0)), (1.5, 20.5));
+    // Too thin to snap
+    assert_eq!(snap_span(1.1, 1.3, None), (1.1, 1.3));
+}
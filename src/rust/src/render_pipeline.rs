@@ -4,9 +4,10 @@ pub(crate) fn create_render_pipeline(
     pipeline_layout_label: &str,
     pipeline_label: &str,
     bind_group_layouts: &[&wgpu::BindGroupLayout],
-    shader_desc: &wgpu::ShaderModuleDescriptor,
+    shader: &wgpu::ShaderModule,
     vertex_buffer_layouts: &[wgpu::VertexBufferLayout],
     sample_count: u32,
+    depth_stencil: Option<wgpu::DepthStencilState>,
 ) -> wgpu::RenderPipeline {
     let render_pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
         label: Some(pipeline_layout_label),
@@ -14,12 +15,11 @@ pub(crate) fn create_render_pipeline(
         push_constant_ranges: &[],
     });
 
-    let shader = device.create_shader_module(shader_desc);
     device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
         label: Some(pipeline_label),
         layout: Some(&render_pipeline_layout),
         vertex: wgpu::VertexState {
-            module: &shader,
+            module: shader,
             entry_point: "vs_main",
             buffers: vertex_buffer_layouts,
         },
@@ -32,13 +32,13 @@ pub(crate) fn create_render_pipeline(
             polygon_mode: wgpu::PolygonMode::Fill,
             conservative: false,
         },
-        depth_stencil: None,
+        depth_stencil,
         multisample: wgpu::MultisampleState {
             count: sample_count,
             ..Default::default()
         },
         fragment: Some(wgpu::FragmentState {
-            module: &shader,
+            module: shader,
             entry_point: "fs_main",
             targets: &[wgpu::ColorTargetState {
                 format: wgpu::TextureFormat::Rgba8Unorm,
@@ -49,3 +49,22 @@ pub(crate) fn create_render_pipeline(
         multiview: None,
     })
 }
+
+pub(crate) const DEPTH_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
+
+// All the pipelines in the main render pass need the depth buffer, but only the
+// polygons use it to draw each pixel only once (c.f. tessellate.rs). The others
+// are drawn regardless of the depth.
+pub(crate) fn depth_stencil_state(draw_once: bool) -> Option<wgpu::DepthStencilState> {
+    Some(wgpu::DepthStencilState {
+        format: DEPTH_FORMAT,
+        depth_write_enabled: draw_once,
+        depth_compare: if draw_once {
+            wgpu::CompareFunction::Greater
+        } else {
+            wgpu::CompareFunction::Always
+        },
+        stencil: wgpu::StencilState::default(),
+        bias: wgpu::DepthBiasState::default(),
+    })
+}
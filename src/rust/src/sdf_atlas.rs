@@ -0,0 +1,381 @@
+// Tessellated glyphs are anti-aliased only by MSAA, which makes small texts look
+// blotchy. As an alternative, we can rasterize the signed distance field (SDF)
+// of each glyph into a texture atlas, and render the glyphs as textured quads
+// with analytic anti-aliasing.
+
+use std::collections::HashMap;
+
+use rayon::prelude::*;
+
+// The glyphs are rasterized on this size (in pixels per the font height)
+pub(crate) const SDF_GLYPH_SIZE: f32 = 48.0;
+// The distance (in pixels) that the SDF can represent on each side of the edge
+const SDF_SPREAD: f32 = 4.0;
+// The padding around the glyph, which needs to cover the spread
+const SDF_PADDING: u32 = 5;
+
+const ATLAS_WIDTH: u32 = 1024;
+const ATLAS_INITIAL_HEIGHT: u32 = 1024;
+// The default limit of `max_texture_dimension_2d`
+const ATLAS_MAX_HEIGHT: u32 = 8192;
+
+// The number of line segments to approximate a curve
+const QUAD_SEGMENTS: usize = 8;
+const CUBIC_SEGMENTS: usize = 12;
+
+#[derive(Debug, Clone)]
+pub(crate) struct AtlasEntry {
+    // The position and the size of the glyph in the atlas (in pixels)
+    pub(crate) x: u32,
+    pub(crate) y: u32,
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+    // The bottom-left corner of the quad (in pixels on `SDF_GLYPH_SIZE`)
+    pub(crate) origin: [f32; 2],
+}
+
+pub(crate) struct SdfGlyphAtlas {
+    // (key of the face, glyph id) -> entry
+    entries: HashMap<(usize, u16), Option<AtlasEntry>>,
+    pub(crate) pixels: Vec<u8>,
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+
+    // A simple shelf packing
+    shelf_y: u32,
+    shelf_height: u32,
+    cursor_x: u32,
+
+    // If true, the atlas needs to be uploaded to the texture
+    pub(crate) dirty: bool,
+}
+
+impl SdfGlyphAtlas {
+    pub(crate) fn new() -> Self {
+        Self {
+            entries: HashMap::new(),
+            pixels: vec![0; (ATLAS_WIDTH * ATLAS_INITIAL_HEIGHT) as usize],
+            width: ATLAS_WIDTH,
+            height: ATLAS_INITIAL_HEIGHT,
+            shelf_y: 0,
+            shelf_height: 0,
+            cursor_x: 0,
+            dirty: true,
+        }
+    }
+
+    // Returns `None` if the glyph has no outline (e.g. a space) or the atlas is
+    // full.
+    pub(crate) fn get_or_insert(
+        &mut self,
+        face_key: usize,
+        font: &ttf_parser::Face,
+        glyph_id: ttf_parser::GlyphId,
+    ) -> Option<AtlasEntry> {
+        if let Some(entry) = self.entries.get(&(face_key, glyph_id.0)) {
+            return entry.clone();
+        }
+
+        let entry = rasterize_glyph(font, glyph_id).and_then(|sdf| self.insert(sdf));
+        self.entries.insert((face_key, glyph_id.0), entry.clone());
+        entry
+    }
+
+    fn insert(&mut self, sdf: GlyphSdf) -> Option<AtlasEntry> {
+        if sdf.width > self.width {
+            return None;
+        }
+
+        // Move to the next shelf if the current one is full
+        if self.cursor_x + sdf.width > self.width {
+            self.shelf_y += self.shelf_height;
+            self.shelf_height = 0;
+            self.cursor_x = 0;
+        }
+
+        // Grow the atlas if there's no room
+        while self.shelf_y + sdf.height > self.height {
+            if self.height >= ATLAS_MAX_HEIGHT {
+                return None;
+            }
+            self.height *= 2;
+            self.pixels.resize((self.width * self.height) as usize, 0);
+        }
+
+        let (x, y) = (self.cursor_x, self.shelf_y);
+        for row in 0..sdf.height {
+            let src = (row * sdf.width) as usize;
+            let dst = ((y + row) * self.width + x) as usize;
+            self.pixels[dst..(dst + sdf.width as usize)]
+                .copy_from_slice(&sdf.pixels[src..(src + sdf.width as usize)]);
+        }
+
+        self.cursor_x += sdf.width;
+        self.shelf_height = self.shelf_height.max(sdf.height);
+        self.dirty = true;
+
+        Some(AtlasEntry {
+            x,
+            y,
+            width: sdf.width,
+            height: sdf.height,
+            origin: sdf.origin,
+        })
+    }
+}
+
+// The bias on the encoded distance to move the edge outward by `offset` pixels
+// when the glyph is rendered on `size`.
+pub(crate) fn distance_bias(offset: f32, size: f32) -> f32 {
+    offset * (SDF_GLYPH_SIZE / size) / (2.0 * SDF_SPREAD)
+}
+
+struct GlyphSdf {
+    pixels: Vec<u8>,
+    width: u32,
+    height: u32,
+    origin: [f32; 2],
+}
+
+fn rasterize_glyph(font: &ttf_parser::Face, glyph_id: ttf_parser::GlyphId) -> Option<GlyphSdf> {
+    let scale = SDF_GLYPH_SIZE / font.height() as f32;
+
+    let mut builder = SegmentsBuilder::new(scale);
+    let bbox = font.outline_glyph(glyph_id, &mut builder)?;
+
+    let padding = SDF_PADDING as f32;
+    let origin = [
+        bbox.x_min as f32 * scale - padding,
+        bbox.y_min as f32 * scale - padding,
+    ];
+    let width = (bbox.width() as f32 * scale).ceil() as u32 + 2 * SDF_PADDING;
+    let height = (bbox.height() as f32 * scale).ceil() as u32 + 2 * SDF_PADDING;
+
+    // Each pixel checks all the segments, so compute the rows in parallel. The
+    // first row is the top of the glyph.
+    let mut pixels = vec![0; (width * height) as usize];
+    pixels
+        .par_chunks_mut(width as usize)
+        .enumerate()
+        .for_each(|(row, pixels_in_row)| {
+            for (col, pixel) in pixels_in_row.iter_mut().enumerate() {
+                let p = [
+                    origin[0] + col as f32 + 0.5,
+                    origin[1] + (height - row as u32) as f32 - 0.5,
+                ];
+                *pixel = encode_distance(signed_distance(&builder.segments, p));
+            }
+        });
+
+    Some(GlyphSdf {
+        pixels,
+        width,
+        height,
+        origin,
+    })
+}
+
+// 0.5 is on the edge, and larger values are inside.
+fn encode_distance(dist: f32) -> u8 {
+    let v = 0.5 + dist / (2.0 * SDF_SPREAD);
+    (v.clamp(0.0, 1.0) * 255.0).round() as u8
+}
+
+type Segment = ([f32; 2], [f32; 2]);
+
+// The distance to the outline, positive inside and negative outside. The
+// inside is determined by the non-zero winding rule.
+fn signed_distance(segments: &[Segment], p: [f32; 2]) -> f32 {
+    let mut min_dist_sq = f32::INFINITY;
+    let mut winding = 0;
+
+    for (a, b) in segments {
+        let ab = [b[0] - a[0], b[1] - a[1]];
+        let ap = [p[0] - a[0], p[1] - a[1]];
+        let len_sq = ab[0] * ab[0] + ab[1] * ab[1];
+        let t = if len_sq > 0.0 {
+            ((ap[0] * ab[0] + ap[1] * ab[1]) / len_sq).clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+        let d = [ap[0] - ab[0] * t, ap[1] - ab[1] * t];
+        min_dist_sq = min_dist_sq.min(d[0] * d[0] + d[1] * d[1]);
+
+        // Count the crossings of the ray toward +x
+        let cross = ab[0] * ap[1] - ab[1] * ap[0];
+        if a[1] <= p[1] {
+            if b[1] > p[1] && cross > 0.0 {
+                winding += 1;
+            }
+        } else if b[1] <= p[1] && cross < 0.0 {
+            winding -= 1;
+        }
+    }
+
+    let dist = min_dist_sq.sqrt();
+    if winding != 0 {
+        dist
+    } else {
+        -dist
+    }
+}
+
+// Collects the outline as line segments
+struct SegmentsBuilder {
+    segments: Vec<Segment>,
+    scale: f32,
+    start: [f32; 2],
+    current: [f32; 2],
+}
+
+impl SegmentsBuilder {
+    fn new(scale: f32) -> Self {
+        Self {
+            segments: Vec::new(),
+            scale,
+            start: [0.0, 0.0],
+            current: [0.0, 0.0],
+        }
+    }
+
+    fn push(&mut self, to: [f32; 2]) {
+        self.segments.push((self.current, to));
+        self.current = to;
+    }
+}
+
+impl ttf_parser::OutlineBuilder for SegmentsBuilder {
+    fn move_to(&mut self, x: f32, y: f32) {
+        self.start = [x * self.scale, y * self.scale];
+        self.current = self.start;
+    }
+
+    fn line_to(&mut self, x: f32, y: f32) {
+        self.push([x * self.scale, y * self.scale]);
+    }
+
+    fn quad_to(&mut self, x1: f32, y1: f32, x: f32, y: f32) {
+        let p0 = self.current;
+        let p1 = [x1 * self.scale, y1 * self.scale];
+        let p2 = [x * self.scale, y * self.scale];
+        for i in 1..=QUAD_SEGMENTS {
+            let t = i as f32 / QUAD_SEGMENTS as f32;
+            let mt = 1.0 - t;
+            self.push([
+                mt * mt * p0[0] + 2.0 * mt * t * p1[0] + t * t * p2[0],
+                mt * mt * p0[1] + 2.0 * mt * t * p1[1] + t * t * p2[1],
+            ]);
+        }
+    }
+
+    fn curve_to(&mut self, x1: f32, y1: f32, x2: f32, y2: f32, x: f32, y: f32) {
+        let p0 = self.current;
+        let p1 = [x1 * self.scale, y1 * self.scale];
+        let p2 = [x2 * self.scale, y2 * self.scale];
+        let p3 = [x * self.scale, y * self.scale];
+        for i in 1..=CUBIC_SEGMENTS {
+            let t = i as f32 / CUBIC_SEGMENTS as f32;
+            let mt = 1.0 - t;
+            let (c0, c1, c2, c3) = (mt * mt * mt, 3.0 * mt * mt * t, 3.0 * mt * t * t, t * t * t);
+            self.push([
+                c0 * p0[0] + c1 * p1[0] + c2 * p2[0] + c3 * p3[0],
+                c0 * p0[1] + c1 * p1[1] + c2 * p2[1] + c3 * p3[1],
+            ]);
+        }
+    }
+
+    fn close(&mut self) {
+        if self.current != self.start {
+            self.push(self.start);
+        }
+    }
+}
+
+#[test]
+fn test_signed_distance() {
+    // A 10x10 square
+    let square = [
+        ([0.0, 0.0], [10.0, 0.0]),
+        ([10.0, 0.0], [10.0, 10.0]),
+        ([10.0, 10.0], [0.0, 10.0]),
+        ([0.0, 10.0], [0.0, 0.0]),
+    ];
+
+    assert_eq!(signed_distance(&square, [5.0, 5.0]), 5.0);
+    assert_eq!(signed_distance(&square, [1.0, 5.0]), 1.0);
+    assert_eq!(signed_distance(&square, [-2.0, 5.0]), -2.0);
+    assert_eq!(signed_distance(&square, [13.0, 14.0]), -5.0);
+
+    // The winding direction doesn't matter
+    let reversed: Vec<Segment> = square.iter().rev().map(|(a, b)| (*b, *a)).collect();
+    assert_eq!(signed_distance(&reversed, [1.0, 5.0]), 1.0);
+    assert_eq!(signed_distance(&reversed, [-2.0, 5.0]), -2.0);
+}
+
+#[test]
+fn test_encode_distance() {
+    assert_eq!(encode_distance(0.0), 128);
+    assert_eq!(encode_distance(SDF_SPREAD), 255);
+    assert_eq!(encode_distance(-SDF_SPREAD * 2.0), 0);
+}
+
+#[test]
+fn test_atlas_packing() {
+    let glyph = |value: u8| GlyphSdf {
+        pixels: vec![value; 100 * 100],
+        width: 100,
+        height: 100,
+        origin: [0.0, 0.0],
+    };
+    let mut atlas = SdfGlyphAtlas::new();
+
+    // 10 glyphs per shelf, and 10 shelves fit in the initial height
+    let entries: Vec<AtlasEntry> = (0..100)
+        .map(|i| atlas.insert(glyph(i as u8)).unwrap())
+        .collect();
+    assert_eq!((entries[1].x, entries[1].y), (100, 0));
+    assert_eq!((entries[10].x, entries[10].y), (0, 100));
+    assert_eq!((entries[99].x, entries[99].y), (900, 900));
+    assert_eq!(atlas.height, ATLAS_INITIAL_HEIGHT);
+
+    // The next shelf doesn't fit, so the atlas grows and keeps the pixels
+    let entry = atlas.insert(glyph(200)).unwrap();
+    assert_eq!(
+        (entry.x, entry.y, entry.width, entry.height),
+        (0, 1000, 100, 100)
+    );
+    assert_eq!(atlas.height, ATLAS_INITIAL_HEIGHT * 2);
+    assert_eq!(atlas.pixels.len(), (ATLAS_WIDTH * atlas.height) as usize);
+    let pixel = |entry: &AtlasEntry| atlas.pixels[(entry.y * ATLAS_WIDTH + entry.x) as usize];
+    assert_eq!(pixel(&entries[0]), 0);
+    assert_eq!(pixel(&entries[99]), 99);
+    assert_eq!(pixel(&entry), 200);
+
+    // Grows up to the max height, i.e. 81 shelves
+    let mut n_glyphs = 101;
+    while let Some(entry) = atlas.insert(glyph(0)) {
+        assert!(entry.y + entry.height <= ATLAS_MAX_HEIGHT);
+        n_glyphs += 1;
+    }
+    assert_eq!(n_glyphs, 810);
+    assert_eq!(atlas.height, ATLAS_MAX_HEIGHT);
+
+    // Fill the rest with tiny glyphs. Then, a glyph that doesn't fit is cached
+    // as `None`, and not rasterized again.
+    let tiny = || GlyphSdf {
+        pixels: vec![0],
+        width: 1,
+        height: 1,
+        origin: [0.0, 0.0],
+    };
+    while atlas.insert(tiny()).is_some() {}
+
+    let font_data = match crate::text::test_font_data() {
+        Some(data) => data,
+        None => return,
+    };
+    let font = ttf_parser::Face::from_slice(&font_data, 0).unwrap();
+    let glyph_id = font.glyph_index('A').unwrap();
+    assert!(atlas.get_or_insert(0, &font, glyph_id).is_none());
+    assert!(matches!(atlas.entries.get(&(0, glyph_id.0)), Some(None)));
+}
@@ -0,0 +1,291 @@
+// Translating WGSL into the backend's shader language takes a noticeable time
+// on every session, which matters on short knitr chunks. On Vulkan, wgpu can
+// take SPIR-V as it is, so the translated SPIR-V is cached on disk and the
+// translation happens only once per shader and adapter. The driver compiles
+// SPIR-V into the native code, but the drivers usually have their own cache
+// for that. On the other backends, this simply falls back to WGSL.
+//
+// The SPIR-V passed through skips wgpu's own translation, so it must be what
+// wgpu-hal would generate; otherwise, for example, the Y axis is flipped twice
+// (wgpu-hal flips it by the viewport, not in the shader). c.f. `spv_options()`.
+
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
+
+use extendr_api::prelude::*;
+
+const SPIRV_MAGIC_NUMBER: u32 = 0x0723_0203;
+
+pub(crate) struct ShaderCache {
+    // `None` if the cache is not available on the backend, or the directory
+    // cannot be determined
+    dir: Option<PathBuf>,
+    // Identifies the adapter so that the cache is not shared between GPUs
+    adapter_key: String,
+    spv_options: naga::back::spv::Options,
+}
+
+impl ShaderCache {
+    // `dir` is the directory to store the cache, usually `cache_dir()`.
+    pub(crate) fn new(
+        adapter_info: &wgpu::AdapterInfo,
+        features: wgpu::Features,
+        dir: Option<PathBuf>,
+    ) -> Self {
+        let dir = dir.filter(|_| {
+            adapter_info.backend == wgpu::Backend::Vulkan
+                && features.contains(wgpu::Features::SPIRV_SHADER_PASSTHROUGH)
+        });
+
+        let spv_options = spv_options(adapter_info);
+
+        let adapter_key = format!(
+            "{}:{:x}:{:x}:{:?}:{}:{:x}",
+            adapter_info.name,
+            adapter_info.vendor,
+            adapter_info.device,
+            adapter_info.backend,
+            // The output of the translation might change between versions
+            env!("CARGO_PKG_VERSION"),
+            spv_options.flags.bits()
+        );
+
+        Self {
+            dir,
+            adapter_key,
+            spv_options,
+        }
+    }
+
+    pub(crate) fn create_shader_module(
+        &self,
+        device: &wgpu::Device,
+        label: &str,
+        source: &str,
+    ) -> wgpu::ShaderModule {
+        if let Some(dir) = &self.dir {
+            let hash = fnv1a(&[source.as_bytes(), self.adapter_key.as_bytes()]);
+            let path = dir.join(format!("{label}-{hash:016x}.spv"));
+
+            if let Some(spirv) =
+                read_spirv(&path).or_else(|| translate_and_write(&path, source, &self.spv_options))
+            {
+                // Safety: the SPIR-V is either what naga generated from the
+                // valid WGSL, or the cache of it (verified by the checksum).
+                return unsafe {
+                    device.create_shader_module_spirv(&wgpu::ShaderModuleDescriptorSpirV {
+                        label: Some(label),
+                        source: Cow::Owned(spirv),
+                    })
+                };
+            }
+        }
+
+        device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+            label: Some(label),
+            source: wgpu::ShaderSource::Wgsl(Cow::Borrowed(source)),
+        })
+    }
+}
+
+// The cache directory, e.g. ~/.cache/R/wgpugd on Linux. Note that this must be
+// called on the R's main thread.
+pub(crate) fn cache_dir() -> Option<PathBuf> {
+    let dir = eval_string("tools::R_user_dir('wgpugd', which = 'cache')").ok()?;
+    dir.as_str().map(PathBuf::from)
+}
+
+fn read_spirv(path: &Path) -> Option<Vec<u32>> {
+    decode_cache(&std::fs::read(path).ok()?)
+}
+
+// The cache file is the checksum of the SPIR-V followed by the SPIR-V. The
+// SPIR-V is passed to the driver without validation, so a truncated or
+// corrupted file must never be used.
+fn encode_cache(spirv: &[u32]) -> Vec<u8> {
+    let spirv_bytes: Vec<u8> = spirv.iter().flat_map(|w| w.to_le_bytes()).collect();
+    let mut bytes = fnv1a(&[&spirv_bytes]).to_le_bytes().to_vec();
+    bytes.extend(spirv_bytes);
+    bytes
+}
+
+// Returns `None` for the broken files.
+fn decode_cache(bytes: &[u8]) -> Option<Vec<u32>> {
+    if bytes.len() < 8 {
+        return None;
+    }
+
+    let (checksum, spirv_bytes) = bytes.split_at(8);
+    if u64::from_le_bytes(checksum.try_into().ok()?) != fnv1a(&[spirv_bytes]) {
+        return None;
+    }
+
+    let chunks = spirv_bytes.chunks_exact(4);
+    if !chunks.remainder().is_empty() {
+        return None;
+    }
+
+    let words: Vec<u32> = chunks
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect();
+
+    if words.first() != Some(&SPIRV_MAGIC_NUMBER) {
+        return None;
+    }
+
+    Some(words)
+}
+
+// Failing to write the cache is not an error; it just means the translation
+// happens again next time.
+fn translate_and_write(
+    path: &Path,
+    source: &str,
+    options: &naga::back::spv::Options,
+) -> Option<Vec<u32>> {
+    let module = naga::front::wgsl::parse_str(source).ok()?;
+    // wgpu-core enables the capabilities by the device features, none of which
+    // wgpugd requests.
+    let info = naga::valid::Validator::new(
+        naga::valid::ValidationFlags::all(),
+        naga::valid::Capabilities::empty(),
+    )
+    .validate(&module)
+    .ok()?;
+    let spirv = naga::back::spv::write_vec(&module, &info, options, None).ok()?;
+
+    let bytes = encode_cache(&spirv);
+
+    // Write to a temporary file first so that other R sessions never read a
+    // half-written file.
+    if let Some(dir) = path.parent() {
+        let tmp = path.with_extension(format!("spv.{}", std::process::id()));
+        let res = std::fs::create_dir_all(dir)
+            .and_then(|_| std::fs::write(&tmp, &bytes))
+            .and_then(|_| std::fs::rename(&tmp, path));
+        if res.is_err() {
+            let _ = std::fs::remove_file(&tmp);
+        }
+    }
+
+    Some(spirv)
+}
+
+// The same options as wgpu-hal's Vulkan backend uses (c.f. `naga_options` in
+// wgpu-hal/src/vulkan/adapter.rs). Notably, `ADJUST_COORDINATE_SPACE`, which is
+// on by default, is off. wgpu-hal omits the bounds checks if the device has the
+// robust buffer or image access, which is not visible through wgpu; the checks
+// are always on here, which produces the same result for the valid accesses.
+fn spv_options(adapter_info: &wgpu::AdapterInfo) -> naga::back::spv::Options {
+    use naga::back::spv;
+
+    const QUALCOMM_VENDOR_ID: usize = 0x5143;
+
+    let capabilities = [
+        spv::Capability::Shader,
+        spv::Capability::Matrix,
+        spv::Capability::Sampled1D,
+        spv::Capability::Image1D,
+        spv::Capability::ImageQuery,
+        spv::Capability::DerivativeControl,
+        spv::Capability::SampledCubeArray,
+        spv::Capability::SampleRateShading,
+        spv::Capability::StorageImageExtendedFormats,
+    ];
+
+    let mut flags = spv::WriterFlags::FORCE_POINT_SIZE;
+    flags.set(
+        spv::WriterFlags::LABEL_VARYINGS,
+        adapter_info.vendor != QUALCOMM_VENDOR_ID,
+    );
+
+    spv::Options {
+        lang_version: (1, 0),
+        flags,
+        capabilities: Some(capabilities.into_iter().collect()),
+        bounds_check_policies: naga::proc::BoundsCheckPolicies {
+            index: naga::proc::BoundsCheckPolicy::Restrict,
+            buffer: naga::proc::BoundsCheckPolicy::Restrict,
+            image: naga::proc::BoundsCheckPolicy::Restrict,
+        },
+    }
+}
+
+// A hash that is stable across Rust versions (unlike `DefaultHasher`), as the
+// cache lives longer than the build.
+fn fnv1a(data: &[&[u8]]) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325_u64;
+    for b in data.iter().flat_map(|d| d.iter()) {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash
+}
+
+#[test]
+fn test_fnv1a() {
+    assert_eq!(fnv1a(&[b""]), 0xcbf2_9ce4_8422_2325);
+    assert_eq!(fnv1a(&[b"a"]), 0xaf63_dc4c_8601_ec8c);
+    assert_eq!(fnv1a(&[b"foo", b"bar"]), fnv1a(&[b"foobar"]));
+}
+
+#[test]
+fn test_cache_checksum() {
+    let spirv = vec![SPIRV_MAGIC_NUMBER, 0x0001_0000, 1, 2, 3];
+    let bytes = encode_cache(&spirv);
+    assert_eq!(decode_cache(&bytes), Some(spirv));
+
+    // Truncated
+    assert_eq!(decode_cache(&bytes[..bytes.len() - 4]), None);
+    assert_eq!(decode_cache(&bytes[..6]), None);
+    // Corrupted
+    let mut corrupted = bytes.clone();
+    corrupted[16] ^= 1;
+    assert_eq!(decode_cache(&corrupted), None);
+    // The old format without the checksum
+    assert_eq!(decode_cache(&bytes[8..]), None);
+}
+
+// The shapes rendered through the cached SPIR-V, both freshly translated and
+// read from the file, must be identical to the ones rendered through WGSL. Note
+// that the cache is used only on Vulkan; on the other backends, this compares
+// WGSL with WGSL.
+#[test]
+fn test_cached_shader_module() {
+    use crate::gpu_context::GpuContext;
+    use crate::gpu_test::{draw_test_sdf, test_gpu_context};
+    use crate::marker::SdfShape;
+    use crate::SDFInstance;
+
+    let uncached = match test_gpu_context() {
+        Some(gpu) => gpu,
+        None => return,
+    };
+
+    // Asymmetric in both directions so that a flipped image differs
+    let black = 0xff000000_u32 as i32;
+    let gray = 0x80000000_u32 as i32;
+    let shapes = [
+        SDFInstance::new(SdfShape::Circle, (16.3, 44.1), (8.0, 8.0), 1.0, gray, black),
+        SDFInstance::new(
+            SdfShape::Triangle,
+            (44.0, 20.0),
+            (10.0, 10.0),
+            2.0,
+            black,
+            black,
+        ),
+    ];
+    let expected = draw_test_sdf(uncached, &shapes);
+
+    let dir = std::env::temp_dir().join(format!("wgpugd-test-{}", std::process::id()));
+    // The first context writes the cache, and the second one reads it.
+    for _ in 0..2 {
+        let cached = pollster::block_on(GpuContext::new(Some(dir.clone())))
+            .expect("The adapter is found once");
+        let image = draw_test_sdf(Box::leak(Box::new(cached)), &shapes);
+        assert!(image == expected, "The cached shader renders differently");
+    }
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,88 @@
+struct InstanceInput {
+    // An affine transform that maps the unit square to the quad of the glyph
+    @location(0) transform_x: vec2<f32>,
+    @location(1) transform_y: vec2<f32>,
+    @location(2) translation: vec2<f32>,
+    // The top-left corner and the size of the glyph in the atlas, in texels
+    @location(3) texel_min:   vec2<f32>,
+    @location(4) texel_size:  vec2<f32>,
+    @location(5) color:       u32,
+    // The bias on the distance to thicken the glyph
+    @location(6) bias:        f32,
+};
+
+struct VertexOutput {
+    @builtin(position) coords: vec4<f32>,
+    @location(0) uv:           vec2<f32>,
+    @location(1) color:        u32,
+    @location(2) bias:         f32,
+};
+
+struct GlobalsUniform {
+    @location(0) resolution: vec2<f32>,
+    // The pixels of the framebuffer per point (more than 1 on supersampling)
+    @location(1) scale:      f32,
+};
+
+@group(0) @binding(0)
+var<uniform> globals: GlobalsUniform;
+
+// `clip` and `clip_coverage()` are prepended from clip.wgsl
+
+@group(1) @binding(0)
+var atlas_texture: texture_2d<f32>;
+@group(1) @binding(1)
+var atlas_sampler: sampler;
+
+@vertex
+fn vs_main(
+    @builtin(vertex_index) vertex_index: u32,
+    instance: InstanceInput,
+) -> VertexOutput {
+    var vs_out: VertexOutput;
+
+    // Two triangles of the unit square
+    var corners = array<vec2<f32>, 6>(
+        vec2<f32>(0.0, 0.0),
+        vec2<f32>(1.0, 0.0),
+        vec2<f32>(1.0, 1.0),
+        vec2<f32>(0.0, 0.0),
+        vec2<f32>(1.0, 1.0),
+        vec2<f32>(0.0, 1.0),
+    );
+    let corner = corners[vertex_index];
+
+    let pos = instance.transform_x * corner.x + instance.transform_y * corner.y + instance.translation;
+
+    // Scale the X and Y positions from [0, width or height] to [-1, 1]
+    vs_out.coords = vec4<f32>(2.0 * pos / globals.resolution - 1.0, 0.0, 1.0);
+
+    // The first row of the texture is the top of the glyph. The atlas may have
+    // grown since the instance was made, so normalize with the current size.
+    let texel = instance.texel_min + vec2<f32>(corner.x, 1.0 - corner.y) * instance.texel_size;
+    vs_out.uv = texel / vec2<f32>(textureDimensions(atlas_texture));
+    vs_out.color = instance.color;
+    vs_out.bias = instance.bias;
+
+    return vs_out;
+}
+
+@fragment
+fn fs_main(
+    vs_out: VertexOutput
+) -> @location(0) vec4<f32> {
+    var color: vec4<f32> = unpack4x8unorm(vs_out.color);
+
+    // The distance is encoded as 0.5 on the edge, and larger inside.
+    let dist = textureSample(atlas_texture, atlas_sampler, vs_out.uv).r - 0.5 + vs_out.bias;
+
+    // Analytic anti-aliasing: how much the distance changes per pixel on
+    // screen tells the width of the edge to smooth.
+    let width = length(vec2<f32>(dpdx(dist), dpdy(dist)));
+    color.a *= clamp(dist / max(width, 0.0001) + 0.5, 0.0, 1.0);
+
+    color.a *= clip_coverage(vs_out.coords.xy);
+
+    // return the alpha-premultiplied version of value
+    return vec4<f32>(color.rgb * color.a, color.a);
+}
@@ -0,0 +1,21 @@
+// The clip rect shared by the shaders of the shapes. This is prepended to them
+// on creating the modules (c.f. gpu_context.rs), next to the globals that each
+// of them declares at binding 0.
+
+// The clip rect (left, top, right, bottom) in the pixels of the framebuffer.
+// Unless the clipping falls on the pixel boundaries, this is applied here
+// instead of the scissor.
+struct ClipUniform {
+    @location(0) bounds: vec4<f32>,
+};
+
+@group(0) @binding(1)
+var<uniform> clip: ClipUniform;
+
+// How much of the pixel is inside the clip rect
+fn clip_coverage(coords: vec2<f32>) -> f32 {
+    let p = floor(coords);
+    let overlap = min(p + 1.0, clip.bounds.zw) - max(p, clip.bounds.xy);
+    let covered = clamp(overlap, vec2<f32>(0.0), vec2<f32>(1.0));
+    return covered.x * covered.y;
+}
@@ -0,0 +1,72 @@
+// Downsamples the supersampled framebuffer into the output size.
+
+struct DownsampleParams {
+    @location(0) factor: u32,
+    // 0: box, 1: Lanczos. These must match with `DownsampleFilter`.
+    @location(1) kernel: u32,
+};
+
+@group(0) @binding(0)
+var source: texture_2d<f32>;
+@group(0) @binding(1)
+var<uniform> params: DownsampleParams;
+
+@vertex
+fn vs_main(@builtin(vertex_index) vertex_index: u32) -> @builtin(position) vec4<f32> {
+    // A triangle that covers the whole target
+    let uv = vec2<f32>(f32((vertex_index << 1u) & 2u), f32(vertex_index & 2u));
+    return vec4<f32>(uv * 2.0 - 1.0, 0.0, 1.0);
+}
+
+let PI = 3.14159265;
+
+// The Lanczos kernel with a = 2
+fn lanczos2(x: f32) -> f32 {
+    if (abs(x) < 0.0001) {
+        return 1.0;
+    }
+    if (abs(x) >= 2.0) {
+        return 0.0;
+    }
+    let px = PI * x;
+    return 2.0 * sin(px) * sin(px * 0.5) / (px * px);
+}
+
+@fragment
+fn fs_main(@builtin(position) coords: vec4<f32>) -> @location(0) vec4<f32> {
+    let n = i32(params.factor);
+    let size = vec2<i32>(textureDimensions(source));
+    // The top-left texel of the output pixel
+    let base = vec2<i32>(floor(coords.xy)) * n;
+
+    var sum = vec4<f32>(0.0);
+
+    if (params.kernel == 0u) {
+        for (var y: i32 = 0; y < n; y = y + 1) {
+            for (var x: i32 = 0; x < n; x = x + 1) {
+                sum = sum + textureLoad(source, base + vec2<i32>(x, y), 0);
+            }
+        }
+        return sum / f32(n * n);
+    }
+
+    // The kernel spans 2 output pixels on each side, i.e. 2n texels.
+    let center = (floor(coords.xy) + 0.5) * f32(n);
+    var weight_sum = 0.0;
+    for (var y: i32 = -2 * n; y < 3 * n; y = y + 1) {
+        let wy = lanczos2((f32(base.y + y) + 0.5 - center.y) / f32(n));
+        if (wy == 0.0) {
+            continue;
+        }
+        for (var x: i32 = -2 * n; x < 3 * n; x = x + 1) {
+            let wx = lanczos2((f32(base.x + x) + 0.5 - center.x) / f32(n));
+            // Extend the edges
+            let p = clamp(base + vec2<i32>(x, y), vec2<i32>(0), size - 1);
+            sum = sum + textureLoad(source, p, 0) * wx * wy;
+            weight_sum = weight_sum + wx * wy;
+        }
+    }
+
+    // The negative lobes can overshoot
+    return clamp(sum / weight_sum, vec4<f32>(0.0), vec4<f32>(1.0));
+}
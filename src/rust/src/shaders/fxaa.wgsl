@@ -0,0 +1,72 @@
+// FXAA; smooths the edges of the rendered image by blurring along the edges.
+// This is the simple variant described in Timothy Lottes' "FXAA" whitepaper.
+
+@group(0) @binding(0)
+var source: texture_2d<f32>;
+@group(0) @binding(1)
+var source_sampler: sampler;
+
+@vertex
+fn vs_main(@builtin(vertex_index) vertex_index: u32) -> @builtin(position) vec4<f32> {
+    // A triangle that covers the whole target
+    let uv = vec2<f32>(f32((vertex_index << 1u) & 2u), f32(vertex_index & 2u));
+    return vec4<f32>(uv * 2.0 - 1.0, 0.0, 1.0);
+}
+
+let REDUCE_MIN = 0.0078125; // 1 / 128
+let REDUCE_MUL = 0.125;     // 1 / 8
+let SPAN_MAX = 8.0;
+
+let LUMA = vec3<f32>(0.299, 0.587, 0.114);
+
+fn sample_at(uv: vec2<f32>) -> vec4<f32> {
+    return textureSampleLevel(source, source_sampler, uv, 0.0);
+}
+
+@fragment
+fn fs_main(@builtin(position) coords: vec4<f32>) -> @location(0) vec4<f32> {
+    let texel = 1.0 / vec2<f32>(textureDimensions(source));
+    let uv = coords.xy * texel;
+
+    let luma_nw = dot(sample_at(uv + vec2<f32>(-1.0, -1.0) * texel).rgb, LUMA);
+    let luma_ne = dot(sample_at(uv + vec2<f32>(1.0, -1.0) * texel).rgb, LUMA);
+    let luma_sw = dot(sample_at(uv + vec2<f32>(-1.0, 1.0) * texel).rgb, LUMA);
+    let luma_se = dot(sample_at(uv + vec2<f32>(1.0, 1.0) * texel).rgb, LUMA);
+    let color_m = sample_at(uv);
+    let luma_m = dot(color_m.rgb, LUMA);
+
+    let luma_min = min(luma_m, min(min(luma_nw, luma_ne), min(luma_sw, luma_se)));
+    let luma_max = max(luma_m, max(max(luma_nw, luma_ne), max(luma_sw, luma_se)));
+
+    // Not on an edge
+    if (luma_max - luma_min < REDUCE_MIN) {
+        return color_m;
+    }
+
+    // The direction along the edge
+    var dir = vec2<f32>(
+        (luma_sw + luma_se) - (luma_nw + luma_ne),
+        (luma_nw + luma_sw) - (luma_ne + luma_se),
+    );
+
+    let dir_reduce = max((luma_nw + luma_ne + luma_sw + luma_se) * 0.25 * REDUCE_MUL, REDUCE_MIN);
+    let rcp_dir_min = 1.0 / (min(abs(dir.x), abs(dir.y)) + dir_reduce);
+    dir = clamp(dir * rcp_dir_min, vec2<f32>(-SPAN_MAX), vec2<f32>(SPAN_MAX)) * texel;
+
+    let color_a = 0.5 * (
+        sample_at(uv + dir * (1.0 / 3.0 - 0.5)) +
+        sample_at(uv + dir * (2.0 / 3.0 - 0.5))
+    );
+    let color_b = color_a * 0.5 + 0.25 * (
+        sample_at(uv + dir * -0.5) +
+        sample_at(uv + dir * 0.5)
+    );
+
+    // If the wider samples go beyond the local range, they crossed another
+    // edge, so use the narrower ones.
+    let luma_b = dot(color_b.rgb, LUMA);
+    if (luma_b < luma_min || luma_b > luma_max) {
+        return color_a;
+    }
+    return color_b;
+}
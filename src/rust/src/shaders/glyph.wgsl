@@ -0,0 +1,55 @@
+struct VertexInput {
+    @location(0) pos: vec2<f32>,
+};
+
+struct InstanceInput {
+    @location(1) transform_x: vec2<f32>,
+    @location(2) transform_y: vec2<f32>,
+    @location(3) translation: vec2<f32>,
+    @location(4) color:       u32,
+};
+
+struct VertexOutput {
+    @builtin(position) coords: vec4<f32>,
+    @location(0) color:        u32,
+};
+
+struct GlobalsUniform {
+    @location(0) resolution: vec2<f32>,
+    // The pixels of the framebuffer per point (more than 1 on supersampling)
+    @location(1) scale:      f32,
+};
+
+@group(0) @binding(0)
+var<uniform> globals: GlobalsUniform;
+
+// `clip` and `clip_coverage()` are prepended from clip.wgsl
+
+@vertex
+fn vs_main(
+    model: VertexInput,
+    instance: InstanceInput,
+) -> VertexOutput {
+    var vs_out: VertexOutput;
+
+    vs_out.color = instance.color;
+
+    // Apply the affine transform of the instance to place the glyph
+    let pos = instance.transform_x * model.pos.x + instance.transform_y * model.pos.y + instance.translation;
+
+    // Scale the X and Y positions from [0, width or height] to [-1, 1]
+    vs_out.coords = vec4<f32>(2.0 * pos / globals.resolution - 1.0, 0.0, 1.0);
+
+    return vs_out;
+}
+
+@fragment
+fn fs_main(
+    vs_out: VertexOutput
+) -> @location(0) vec4<f32> {
+    var color: vec4<f32> = unpack4x8unorm(vs_out.color);
+    color.a *= clip_coverage(vs_out.coords.xy);
+
+    // return the alpha-premultiplied version of value
+    return vec4<f32>(color.rgb * color.a, color.a);
+}
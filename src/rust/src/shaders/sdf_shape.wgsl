@@ -4,29 +4,51 @@ struct VertexInput {
 
 struct VertexOutput {
     @builtin(position) coords: vec4<f32>,
-    @location(0) center:       vec2<f32>,
-    @location(1) radius:       f32,
-    @location(2) stroke_width: f32,
-    @location(3) fill_color:   u32,
-    @location(4) stroke_color: u32,
-    
+    @location(0) center:        vec2<f32>,
+    @location(1) half_size:     vec2<f32>,
+    @location(2) corner_radius: f32,
+    @location(3) arm_width:     f32,
+    @location(4) stroke_width:  f32,
+    @location(5) fill_color:    u32,
+    @location(6) stroke_color:  u32,
+    @location(7) shape:         u32,
 };
 
 struct GlobalsUniform {
     @location(0) resolution: vec2<f32>,
+    // The pixels of the framebuffer per point (more than 1 on supersampling)
+    @location(1) scale:      f32,
 };
 
 @group(0) @binding(0)
 var<uniform> globals: GlobalsUniform;
 
+// `clip` and `clip_coverage()` are prepended from clip.wgsl
+
 struct InstanceInput {
-    @location(1) center:       vec2<f32>,
-    @location(2) radius:       f32,
-    @location(3) stroke_width: f32,
-    @location(4) fill_color:   u32,
-    @location(5) stroke_color: u32,
+    @location(1) center:        vec2<f32>,
+    @location(2) half_size:     vec2<f32>,
+    @location(3) corner_radius: f32,
+    @location(4) arm_width:     f32,
+    @location(5) stroke_width:  f32,
+    @location(6) fill_color:    u32,
+    @location(7) stroke_color:  u32,
+    @location(8) shape:         u32,
 };
 
+// The kinds of the shapes. These must match with `SdfShape` on the Rust side.
+// Note that they are written as literals in the switch below, as the case
+// selectors cannot be constants.
+let SHAPE_CIRCLE = 0u;
+let SHAPE_RECT = 2u;
+let SHAPE_CROSS = 7u;
+let SHAPE_DIAGONAL = 8u;
+
+// Draw on the whole page instead of the bounding box. This is only for
+// comparing the benchmarks, and is turned on by the cargo feature
+// "bench-full-page-sdf".
+let FULL_PAGE = false;
+
 @vertex
 fn vs_main(
     model: VertexInput,
@@ -34,45 +56,184 @@ fn vs_main(
 ) -> VertexOutput {
     var vs_out: VertexOutput;
 
-    vs_out.coords = vec4<f32>(model.pos, 0.0, 1.0);
+    // Only the bounding box of the shape needs to be drawn. Extend it by 1
+    // pixel for anti-aliasing. The shapes rotated by 45 degrees fit in the
+    // square whose half diagonal covers both of the half sizes and the arms.
+    var half_extent = abs(instance.half_size);
+    if (instance.shape == SHAPE_CROSS || instance.shape == SHAPE_DIAGONAL) {
+        half_extent = vec2<f32>((half_extent.x + half_extent.y + instance.arm_width) * 0.70710678);
+    }
+    let extent = half_extent + instance.stroke_width * 0.5 + 1.0;
+    var pos = instance.center + model.pos * extent;
+    if (FULL_PAGE) {
+        pos = (model.pos * 0.5 + 0.5) * globals.resolution;
+    }
+
+    // Scale the X and Y positions from [0, width or height] to [-1, 1]
+    vs_out.coords = vec4<f32>(2.0 * pos / globals.resolution - 1.0, 0.0, 1.0);
     // Y-axis is opposite
     vs_out.center = vec2<f32>(instance.center.x, globals.resolution.y - instance.center.y);
-    vs_out.radius = instance.radius;
+    vs_out.half_size = instance.half_size;
+    vs_out.corner_radius = instance.corner_radius;
+    vs_out.arm_width = instance.arm_width;
     vs_out.stroke_width = instance.stroke_width;
     vs_out.fill_color = instance.fill_color;
     vs_out.stroke_color = instance.stroke_color;
-    
+    vs_out.shape = instance.shape;
+
     return vs_out;
 }
 
+// The signed distance functions. They are taken from
+// https://iquilezles.org/articles/distfunctions2d/
+
+fn sd_box(p: vec2<f32>, b: vec2<f32>) -> f32 {
+    let q = abs(p) - b;
+    return length(max(q, vec2<f32>(0.0))) + min(max(q.x, q.y), 0.0);
+}
+
+fn sd_rounded_box(p: vec2<f32>, b: vec2<f32>, r: f32) -> f32 {
+    return sd_box(p, b - r) - r;
+}
+
+// This is not exact, but good enough for anti-aliasing.
+fn sd_ellipse(p: vec2<f32>, ab: vec2<f32>) -> f32 {
+    let k1 = length(p / ab);
+    let k2 = length(p / (ab * ab));
+    return k1 * (k1 - 1.0) / k2;
+}
+
+// An isosceles triangle whose apex is at the origin and whose base is at y = q.y
+fn sd_triangle_isosceles(p_orig: vec2<f32>, q: vec2<f32>) -> f32 {
+    let p = vec2<f32>(abs(p_orig.x), p_orig.y);
+    let a = p - q * clamp(dot(p, q) / dot(q, q), 0.0, 1.0);
+    let b = p - q * vec2<f32>(clamp(p.x / q.x, 0.0, 1.0), 1.0);
+    let s = -sign(q.y);
+    let d = min(
+        vec2<f32>(dot(a, a), s * (p.x * q.y - p.y * q.x)),
+        vec2<f32>(dot(b, b), s * (p.y - q.y)),
+    );
+    return -sqrt(d.x) * sign(d.y);
+}
+
+fn sd_rhombus(p_orig: vec2<f32>, b: vec2<f32>) -> f32 {
+    let p = abs(p_orig);
+    let ndot = (b.x - 2.0 * p.x) * b.x - (b.y - 2.0 * p.y) * b.y;
+    let h = clamp(ndot / dot(b, b), -1.0, 1.0);
+    let d = length(p - 0.5 * b * vec2<f32>(1.0 - h, 1.0 + h));
+    return d * sign(p.x * b.y + p.y * b.x - b.x * b.y);
+}
+
+fn sd_plus(p: vec2<f32>, b: vec2<f32>, w: f32, r: f32) -> f32 {
+    return min(
+        sd_rounded_box(p, vec2<f32>(b.x, w), r),
+        sd_rounded_box(p, vec2<f32>(w, b.y), r),
+    );
+}
+
+// Rotates the position so that the X-axis goes along the diagonal from the
+// bottom left to the top right.
+fn rotate_45(p: vec2<f32>) -> vec2<f32> {
+    return vec2<f32>(p.x + p.y, p.y - p.x) * 0.70710678;
+}
+
+// The coverage of the pixel by the inside of the distance field. The distance
+// is divided by its change per pixel, so that the edge is smoothed over one
+// pixel regardless of the scale or the distortion of the distance.
+fn coverage(dist: f32) -> f32 {
+    let width = max(length(vec2<f32>(dpdx(dist), dpdy(dist))), 0.0001);
+    return clamp(0.5 - dist / width, 0.0, 1.0);
+}
+
 @fragment
 fn fs_main(vs_out: VertexOutput) -> @location(0) vec4<f32> {
-    // width to apply anti-aliase
-    let HALF_PIXEL = 0.5;
-
     var fill_color:   vec4<f32> = unpack4x8unorm(vs_out.fill_color);
     var stroke_color: vec4<f32> = unpack4x8unorm(vs_out.stroke_color);
 
-    var dist_fill         = distance(vs_out.coords.xy, vs_out.center) - vs_out.radius;
-    var dist_stroke_inner = distance(vs_out.coords.xy, vs_out.center) - (vs_out.radius - vs_out.stroke_width * 0.5);
-    var dist_stroke_outer = distance(vs_out.coords.xy, vs_out.center) - (vs_out.radius + vs_out.stroke_width * 0.5);
+    // The position relative to the center, in points. Flip the Y-axis again
+    // so that "up" is the same as R's.
+    let p = (vs_out.coords.xy / globals.scale - vs_out.center) * vec2<f32>(1.0, -1.0);
+    var b = vs_out.half_size;
+    var half_stroke = vs_out.stroke_width * 0.5;
 
-    // TODO: A poor-man's anti-aliasing. I don't know how to do it correctly atm...
-    fill_color.a *= clamp(HALF_PIXEL - dist_fill, 0.0, 1.0);
-    stroke_color.a *= min(
-        clamp(HALF_PIXEL - dist_stroke_outer, 0.0, 1.0),  // if it's inside of the outer boundary
-        clamp(dist_stroke_inner + HALF_PIXEL, 0.0, 1.0),  // if it's outside of the inner boundary
-    );
+    // A circle smaller than a pixel would fall between the pixel centers and
+    // vanish. Instead, draw it as large as a pixel, and fade it by the area.
+    let min_radius = 0.5 / globals.scale;
+    let radius = b.x + half_stroke;
+    var area_ratio = 1.0;
+    if (vs_out.shape == SHAPE_CIRCLE && radius < min_radius && radius > 0.0) {
+        let k = min_radius / radius;
+        b = b * k;
+        half_stroke = half_stroke * k;
+        area_ratio = 1.0 / (k * k);
+    }
+
+    var dist_fill: f32;
+    switch (vs_out.shape) {
+        case 1u: {  // SHAPE_ELLIPSE
+            dist_fill = sd_ellipse(p, b);
+        }
+        case 2u: {  // SHAPE_RECT
+            dist_fill = sd_box(p, b);
+        }
+        case 3u: {  // SHAPE_ROUNDED_RECT
+            dist_fill = sd_rounded_box(p, b, vs_out.corner_radius);
+        }
+        case 4u: {  // SHAPE_TRIANGLE
+            // A negative height means the triangle points down.
+            let q = vec2<f32>(b.x, 2.0 * abs(b.y));
+            dist_fill = sd_triangle_isosceles(vec2<f32>(p.x, abs(b.y) - p.y * sign(b.y)), q);
+        }
+        case 5u: {  // SHAPE_DIAMOND
+            dist_fill = sd_rhombus(p, b);
+        }
+        case 6u: {  // SHAPE_PLUS
+            dist_fill = sd_plus(p, b, vs_out.arm_width, vs_out.corner_radius);
+        }
+        case 7u: {  // SHAPE_CROSS
+            // A plus rotated by 45 degrees
+            dist_fill = sd_plus(rotate_45(p), b, vs_out.arm_width, vs_out.corner_radius);
+        }
+        case 8u: {  // SHAPE_DIAGONAL
+            // A (rounded) rect rotated by 45 degrees
+            dist_fill = sd_rounded_box(rotate_45(p), b, vs_out.corner_radius);
+        }
+        default: {  // SHAPE_CIRCLE (0u)
+            dist_fill = length(p) - b.x;
+        }
+    }
+
+    var dist_stroke_inner = dist_fill + half_stroke;
+    var dist_stroke_outer = dist_fill - half_stroke;
 
-    // alpha blending
-    var out_a = stroke_color.a + fill_color.a * (1.0 - stroke_color.a);
-    if (out_a == 0.0) {
-        return vec4<f32>(0.0);
-    } else {
-        return vec4<f32>(
-            // return the alpha-premultiplied values, so don't devide by out_a here.
-            stroke_color.rgb * stroke_color.a + fill_color.rgb * fill_color.a * (1.0 - stroke_color.a),
-            out_a
-        );
+    // The joins of the stroke are round by nature of the distance, but rects
+    // need mitre joins.
+    if (vs_out.shape == SHAPE_RECT) {
+        dist_stroke_inner = sd_box(p, max(b - half_stroke, vec2<f32>(0.0)));
+        dist_stroke_outer = sd_box(p, b + half_stroke);
     }
+
+    // The areas of the pixel inside each boundary. The regions are nested, so
+    // are the areas.
+    let cov_outer = coverage(dist_stroke_outer);
+    let cov_fill = min(coverage(dist_fill), cov_outer);
+    let cov_inner = min(coverage(dist_stroke_inner), cov_fill);
+
+    // The alpha-premultiplied colors
+    let fill = vec4<f32>(fill_color.rgb * fill_color.a, fill_color.a);
+    let stroke = vec4<f32>(stroke_color.rgb * stroke_color.a, stroke_color.a);
+
+    // Composite each part of the pixel separately, and sum them up by the
+    // areas. Multiplying the alphas by the coverages and blending them as if
+    // they were independent would darken the boundary between the fill and the
+    // stroke.
+    //
+    //   - inside the stroke: only the fill
+    //   - the inner half of the stroke: the stroke over the fill
+    //   - the outer half of the stroke: only the stroke
+    let out = fill * cov_inner
+        + (stroke + fill * (1.0 - stroke.a)) * (cov_fill - cov_inner)
+        + stroke * (cov_outer - cov_fill);
+
+    return out * area_ratio * clip_coverage(vs_out.coords.xy);
 }
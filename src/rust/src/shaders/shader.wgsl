@@ -1,6 +1,7 @@
 struct VertexInput {
     @location(0) pos:   vec2<f32>,
     @location(1) color: u32,
+    @location(2) depth: f32,
 };
 
 struct VertexOutput {
@@ -10,11 +11,15 @@ struct VertexOutput {
 
 struct GlobalsUniform {
     @location(0) resolution: vec2<f32>,
+    // The pixels of the framebuffer per point (more than 1 on supersampling)
+    @location(1) scale:      f32,
 };
 
 @group(0) @binding(0)
 var<uniform> globals: GlobalsUniform;
 
+// `clip` and `clip_coverage()` are prepended from clip.wgsl
+
 @vertex
 fn vs_main(
     model: VertexInput,
@@ -24,7 +29,7 @@ fn vs_main(
     vs_out.color = model.color;
 
     // Scale the X and Y positions from [0, width or height] to [-1, 1]
-    vs_out.coords = vec4<f32>(2.0 * model.pos.xy / globals.resolution - 1.0, 0.0, 1.0);
+    vs_out.coords = vec4<f32>(2.0 * model.pos.xy / globals.resolution - 1.0, model.depth, 1.0);
 
     return vs_out;
 }
@@ -39,6 +44,8 @@ fn fs_main(
     // https://github.com/wch/r-source/blob/8ebcb33a9f70e729109b1adf60edd5a3b22d3c6f/src/include/R_ext/GraphicsDevice.h#L766-L796
     // https://www.w3.org/TR/WGSL/#unpack-builtin-functions
     var color: vec4<f32> = unpack4x8unorm(vs_out.color);
+    color.a *= clip_coverage(vs_out.coords.xy);
+
     // return the alpha-premultiplied version of value
     return vec4<f32>(color.rgb * color.a, color.a);
 }
@@ -0,0 +1,136 @@
+// Each instance is a segment between two consecutive points of the polylines.
+// The same point buffer is bound twice, the second one shifted by one point.
+struct SegmentInput {
+    @location(0) from_pos:        vec2<f32>,
+    @location(1) from_half_width: f32,
+    @location(2) from_color:      u32,
+    @location(3) from_flags:      u32,
+    @location(4) to_pos:          vec2<f32>,
+    @location(5) to_flags:        u32,
+};
+
+struct VertexOutput {
+    @builtin(position) coords: vec4<f32>,
+    // The position along and across the segment, relative to the start point
+    @location(0) local:        vec2<f32>,
+    @location(1) length:       f32,
+    @location(2) half_width:   f32,
+    @location(3) color:        u32,
+    @location(4) start_cap:    u32,
+    @location(5) end_cap:      u32,
+};
+
+struct GlobalsUniform {
+    @location(0) resolution: vec2<f32>,
+    // The pixels of the framebuffer per point (more than 1 on supersampling)
+    @location(1) scale:      f32,
+};
+
+@group(0) @binding(0)
+var<uniform> globals: GlobalsUniform;
+
+// `clip` and `clip_coverage()` are prepended from clip.wgsl
+
+// These must match with the ones in stroke.rs.
+let FLAG_FIRST = 1u;
+let FLAG_LAST  = 2u;
+let CAP_SHIFT  = 2u;
+let CAP_ROUND  = 0u;
+let CAP_SQUARE = 2u;
+
+@vertex
+fn vs_main(
+    @builtin(vertex_index) vertex_index: u32,
+    segment: SegmentInput,
+) -> VertexOutput {
+    var vs_out: VertexOutput;
+
+    // The last point of a polyline is not connected to the next polyline.
+    if ((segment.from_flags & FLAG_LAST) != 0u) {
+        vs_out.coords = vec4<f32>(0.0, 0.0, 0.0, 1.0);
+        return vs_out;
+    }
+
+    // Two triangles of the unit square
+    var corners = array<vec2<f32>, 6>(
+        vec2<f32>(0.0, 0.0),
+        vec2<f32>(1.0, 0.0),
+        vec2<f32>(1.0, 1.0),
+        vec2<f32>(0.0, 0.0),
+        vec2<f32>(1.0, 1.0),
+        vec2<f32>(0.0, 1.0),
+    );
+    let corner = corners[vertex_index];
+
+    let v = segment.to_pos - segment.from_pos;
+    let len = length(v);
+    var dir = vec2<f32>(1.0, 0.0);
+    if (len > 0.0) {
+        dir = v / len;
+    }
+    let normal = vec2<f32>(-dir.y, dir.x);
+
+    // Cover the caps and the joins, plus 1 pixel for anti-aliasing.
+    let margin = segment.from_half_width + 1.0;
+    let along = mix(-margin, len + margin, corner.x);
+    let across = mix(-margin, margin, corner.y);
+    let pos = segment.from_pos + dir * along + normal * across;
+
+    // Scale the X and Y positions from [0, width or height] to [-1, 1]
+    vs_out.coords = vec4<f32>(2.0 * pos / globals.resolution - 1.0, 0.0, 1.0);
+    vs_out.local = vec2<f32>(along, across);
+    vs_out.length = len;
+    vs_out.half_width = segment.from_half_width;
+    vs_out.color = segment.from_color;
+
+    // The joins are round, so the ends in the middle of a polyline are the
+    // same as round caps.
+    vs_out.start_cap = CAP_ROUND;
+    if ((segment.from_flags & FLAG_FIRST) != 0u) {
+        vs_out.start_cap = segment.from_flags >> CAP_SHIFT;
+    }
+    vs_out.end_cap = CAP_ROUND;
+    if ((segment.to_flags & FLAG_LAST) != 0u) {
+        vs_out.end_cap = segment.to_flags >> CAP_SHIFT;
+    }
+
+    return vs_out;
+}
+
+// The signed distance to a half of the segment. `x` is the position along the
+// axis measured outward from the end.
+fn sd_end(x: f32, y: f32, half_width: f32, cap: u32) -> f32 {
+    if (cap == CAP_ROUND) {
+        return length(vec2<f32>(max(x, 0.0), y)) - half_width;
+    }
+
+    var q = vec2<f32>(x, abs(y) - half_width);
+    if (cap == CAP_SQUARE) {
+        q.x = x - half_width;
+    }
+    return length(max(q, vec2<f32>(0.0))) + min(max(q.x, q.y), 0.0);
+}
+
+// The coverage of the pixel from the signed distance, anti-aliased over the
+// width of a pixel measured by the derivatives (the same as sdf_shape.wgsl).
+fn coverage(dist: f32) -> f32 {
+    let width = max(length(vec2<f32>(dpdx(dist), dpdy(dist))), 0.0001);
+    return clamp(0.5 - dist / width, 0.0, 1.0);
+}
+
+@fragment
+fn fs_main(vs_out: VertexOutput) -> @location(0) vec4<f32> {
+    var dist: f32;
+    if (vs_out.local.x < vs_out.length * 0.5) {
+        dist = sd_end(-vs_out.local.x, vs_out.local.y, vs_out.half_width, vs_out.start_cap);
+    } else {
+        dist = sd_end(vs_out.local.x - vs_out.length, vs_out.local.y, vs_out.half_width, vs_out.end_cap);
+    }
+
+    var color: vec4<f32> = unpack4x8unorm(vs_out.color);
+    color.a *= coverage(dist);
+    color.a *= clip_coverage(vs_out.coords.xy);
+
+    // return the alpha-premultiplied values
+    return vec4<f32>(color.rgb * color.a, color.a);
+}
@@ -0,0 +1,51 @@
+// Process-wide counters to see whether the caches and the batching work as
+// expected. They are exposed to R via `wgpugd_stats()` for benchmarking.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub(crate) struct Counter(AtomicU64);
+
+impl Counter {
+    const fn new() -> Self {
+        Self(AtomicU64::new(0))
+    }
+
+    pub(crate) fn incr(&self) {
+        self.add(1);
+    }
+
+    pub(crate) fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub(crate) fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn reset(&self) {
+        self.0.store(0, Ordering::Relaxed);
+    }
+}
+
+// The number of char_metric() calls from R
+pub(crate) static CHAR_METRIC_CALLS: Counter = Counter::new();
+// The number of font lookups (i.e. queries to fontdb or systemfonts)
+pub(crate) static FONT_QUERIES: Counter = Counter::new();
+// The number of glyph metric calculations done by ttf_parser
+pub(crate) static GLYPH_METRIC_LOOKUPS: Counter = Counter::new();
+// The number of draw calls on the render pass
+pub(crate) static DRAW_CALLS: Counter = Counter::new();
+// The number of pipeline, bind group, vertex buffer, and index buffer settings
+// on the render pass
+pub(crate) static STATE_CHANGES: Counter = Counter::new();
+// The number of GPU buffers created for the vertices, indices, and instances
+pub(crate) static BUFFER_ALLOCATIONS: Counter = Counter::new();
+
+pub(crate) fn reset_all() {
+    CHAR_METRIC_CALLS.reset();
+    FONT_QUERIES.reset();
+    GLYPH_METRIC_LOOKUPS.reset();
+    DRAW_CALLS.reset();
+    STATE_CHANGES.reset();
+    BUFFER_ALLOCATIONS.reset();
+}
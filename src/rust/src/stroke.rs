@@ -0,0 +1,214 @@
+// Lines are drawn on GPU instead of tessellating the strokes by lyon. The raw
+// points of the polylines are uploaded, and each pair of consecutive points is
+// expanded into a segment in the vertex shader; the caps and the joins are
+// computed as SDF in the fragment shader.
+//
+// The joins are always round, and a translucent polyline would be darker at
+// the joins as the segments overlap there. Such cases fall back to lyon.
+
+use lyon::tessellation::LineCap;
+
+// These must match with the ones in `stroke.wgsl`.
+const FLAG_FIRST: u32 = 1;
+const FLAG_LAST: u32 = 2;
+const CAP_SHIFT: u32 = 2;
+const CAP_ROUND: u32 = 0;
+const CAP_BUTT: u32 = 1;
+const CAP_SQUARE: u32 = 2;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, bytemuck::Pod, bytemuck::Zeroable)]
+pub(crate) struct StrokePoint {
+    pub(crate) position: [f32; 2],
+    half_width: f32,
+    color: u32,
+    // Whether the point is the first or the last of the polyline, and the cap
+    flags: u32,
+}
+
+impl StrokePoint {
+    // The point buffer is bound twice; as the start and as the end of the
+    // segments.
+    const FROM_ATTRIBS: [wgpu::VertexAttribute; 4] = [
+        wgpu::VertexAttribute {
+            format: wgpu::VertexFormat::Float32x2,
+            offset: 0,
+            shader_location: 0,
+        },
+        wgpu::VertexAttribute {
+            format: wgpu::VertexFormat::Float32,
+            offset: 8,
+            shader_location: 1,
+        },
+        wgpu::VertexAttribute {
+            format: wgpu::VertexFormat::Uint32,
+            offset: 12,
+            shader_location: 2,
+        },
+        wgpu::VertexAttribute {
+            format: wgpu::VertexFormat::Uint32,
+            offset: 16,
+            shader_location: 3,
+        },
+    ];
+
+    const TO_ATTRIBS: [wgpu::VertexAttribute; 2] = [
+        wgpu::VertexAttribute {
+            format: wgpu::VertexFormat::Float32x2,
+            offset: 0,
+            shader_location: 4,
+        },
+        wgpu::VertexAttribute {
+            format: wgpu::VertexFormat::Uint32,
+            offset: 16,
+            shader_location: 5,
+        },
+    ];
+
+    pub(crate) fn desc_from<'a>() -> wgpu::VertexBufferLayout<'a> {
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<Self>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Instance,
+            attributes: &Self::FROM_ATTRIBS,
+        }
+    }
+
+    pub(crate) fn desc_to<'a>() -> wgpu::VertexBufferLayout<'a> {
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<Self>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Instance,
+            attributes: &Self::TO_ATTRIBS,
+        }
+    }
+}
+
+pub(crate) const STROKE_POINT_SIZE: u64 = std::mem::size_of::<StrokePoint>() as u64;
+
+// Returns the number of the points pushed.
+pub(crate) fn push_polyline<T: IntoIterator<Item = (f64, f64)>>(
+    points: &mut Vec<StrokePoint>,
+    coords: T,
+    line_width: f32,
+    line_cap: LineCap,
+    color: i32,
+) -> u32 {
+    let cap = match line_cap {
+        LineCap::Round => CAP_ROUND,
+        LineCap::Butt => CAP_BUTT,
+        LineCap::Square => CAP_SQUARE,
+    };
+
+    let begin = points.len();
+    points.extend(coords.into_iter().map(|(x, y)| StrokePoint {
+        position: [x as _, y as _],
+        half_width: line_width / 2.0,
+        color: unsafe { std::mem::transmute(color) },
+        flags: cap << CAP_SHIFT,
+    }));
+
+    let n = points.len() - begin;
+    if n > 0 {
+        points[begin].flags |= FLAG_FIRST;
+        points[begin + n - 1].flags |= FLAG_LAST;
+    }
+
+    n as _
+}
+
+#[test]
+fn test_push_polyline() {
+    let mut points = Vec::new();
+
+    let n = push_polyline(&mut points, [(0.0, 0.0), (1.0, 1.0)], 2.0, LineCap::Butt, 0);
+    assert_eq!(n, 2);
+    let n = push_polyline(
+        &mut points,
+        [(0.0, 0.0), (1.0, 1.0), (2.0, 0.0)],
+        2.0,
+        LineCap::Round,
+        0,
+    );
+    assert_eq!(n, 3);
+
+    let flags: Vec<u32> = points.iter().map(|p| p.flags).collect();
+    assert_eq!(
+        flags,
+        vec![
+            FLAG_FIRST | CAP_BUTT << CAP_SHIFT,
+            FLAG_LAST | CAP_BUTT << CAP_SHIFT,
+            FLAG_FIRST,
+            0,
+            FLAG_LAST,
+        ]
+    );
+    assert_eq!(points[0].half_width, 1.0);
+}
+
+#[test]
+fn test_batched_polylines() {
+    use crate::gpu_test::{create_test_texture, draw_test, read_test_texture, test_gpu_context};
+    use wgpu::util::DeviceExt;
+
+    let gpu = match test_gpu_context() {
+        Some(gpu) => gpu,
+        None => return,
+    };
+
+    // Two horizontal polylines in one draw call. The end of the first one and
+    // the start of the second one are at the opposite corners, so the segment
+    // between them would cross the center.
+    let black = 0xff000000_u32 as i32;
+    let mut points = Vec::new();
+    push_polyline(
+        &mut points,
+        [(8.0, 16.0), (56.0, 16.0)],
+        2.0,
+        LineCap::Butt,
+        black,
+    );
+    push_polyline(
+        &mut points,
+        [(8.0, 48.0), (56.0, 48.0)],
+        2.0,
+        LineCap::Butt,
+        black,
+    );
+
+    // The same points as the start and the end of the segments
+    let buffer = |points: &[StrokePoint]| {
+        gpu.device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: None,
+                contents: bytemuck::cast_slice(points),
+                usage: wgpu::BufferUsages::VERTEX,
+            })
+    };
+    let from_buffer = buffer(&points);
+    let to_buffer = buffer(&points[1..]);
+
+    let texture = create_test_texture(gpu, 1);
+    draw_test(
+        gpu,
+        &texture.create_view(&wgpu::TextureViewDescriptor::default()),
+        1,
+        1,
+        |p| &p.stroke_render_pipeline,
+        &[&from_buffer, &to_buffer],
+        None,
+        6,
+        points.len() as u32 - 1,
+    );
+    let image = read_test_texture(gpu, &texture, 1);
+    let pixel = |x: usize, y: usize| image[y * crate::gpu_test::TEST_SIZE as usize + x];
+
+    // Both of the lines are drawn (the Y-axis of the image is opposite)
+    assert!(pixel(32, 47) < 0.1 && pixel(32, 48) < 0.1);
+    assert!(pixel(32, 15) < 0.1 && pixel(32, 16) < 0.1);
+
+    // Nothing between them
+    for y in 20..44 {
+        for x in 0..crate::gpu_test::TEST_SIZE as usize {
+            assert_eq!(pixel(x, y), 1.0, "({x}, {y}) is drawn");
+        }
+    }
+}
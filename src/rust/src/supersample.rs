@@ -0,0 +1,105 @@
+// MSAA only smooths the edges of the geometries, and supports at most 8
+// samples. For the best quality (e.g. for print), the page can be rendered at
+// N times the resolution, and then downsampled into the output size.
+
+use wgpu::util::DeviceExt;
+
+use crate::gpu_context::GpuContext;
+
+// These must match with the ones in `downsample.wgsl`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u32)]
+pub(crate) enum DownsampleFilter {
+    Box = 0,
+    Lanczos = 1,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct DownsampleParams {
+    factor: u32,
+    kernel: u32,
+}
+
+pub(crate) struct Supersampler {
+    // The framebuffer of the N times size
+    pub(crate) view: wgpu::TextureView,
+    bind_group: wgpu::BindGroup,
+}
+
+impl Supersampler {
+    pub(crate) fn new(
+        gpu: &GpuContext,
+        extent: wgpu::Extent3d,
+        factor: u32,
+        filter: DownsampleFilter,
+    ) -> Self {
+        let device = &gpu.device;
+
+        let view = device
+            .create_texture(&wgpu::TextureDescriptor {
+                label: Some("wgpugd supersampled framebuffer"),
+                size: extent,
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: wgpu::TextureFormat::Rgba8Unorm,
+                // This is rendered to, and then read by the downsampling
+                usage: wgpu::TextureUsages::RENDER_ATTACHMENT
+                    | wgpu::TextureUsages::TEXTURE_BINDING,
+            })
+            .create_view(&wgpu::TextureViewDescriptor::default());
+
+        let params_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("wgpugd uniform buffer for downsampling"),
+            contents: bytemuck::cast_slice(&[DownsampleParams {
+                factor,
+                kernel: filter as _,
+            }]),
+            usage: wgpu::BufferUsages::UNIFORM,
+        });
+
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("wgpugd bind group for downsampling"),
+            layout: &gpu.downsample_bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: params_buffer.as_entire_binding(),
+                },
+            ],
+        });
+
+        Self { view, bind_group }
+    }
+
+    // Draws the downsampled image onto the target.
+    pub(crate) fn downsample(
+        &self,
+        gpu: &GpuContext,
+        encoder: &mut wgpu::CommandEncoder,
+        target: &wgpu::TextureView,
+    ) {
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("wgpugd downsampling render pass"),
+            color_attachments: &[wgpu::RenderPassColorAttachment {
+                view: target,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::WHITE),
+                    store: true,
+                },
+            }],
+            depth_stencil_attachment: None,
+        });
+
+        render_pass.set_pipeline(&gpu.downsample_pipeline);
+        render_pass.set_bind_group(0, &self.bind_group, &[]);
+        // The triangle covering the whole target is generated in the shader
+        render_pass.draw(0..3, 0..1);
+    }
+}
@@ -0,0 +1,92 @@
+// systemfonts (https://github.com/r-lib/systemfonts) is the font registry that
+// other devices like ragg and svglite rely on; users register their own fonts
+// via `systemfonts::register_font()` and expect every device to see them. So,
+// if the package is installed, we ask it where the font file is, instead of
+// resolving the family name by ourselves.
+
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_void};
+use std::path::PathBuf;
+
+use extendr_api::prelude::*;
+use once_cell::sync::OnceCell;
+
+// The signature of the C-callable function:
+//
+// int locate_font(const char *family, int italic, int bold, char *path, int max_path_length)
+//
+// ref: https://github.com/r-lib/systemfonts/blob/main/inst/include/systemfonts.h
+type LocateFontFn = unsafe extern "C" fn(*const c_char, c_int, c_int, *mut c_char, c_int) -> c_int;
+
+// This should be enough for PATH_MAX on most of the platforms.
+const MAX_PATH_LENGTH: usize = 4096;
+
+pub(crate) struct SystemFonts {
+    locate_font: LocateFontFn,
+}
+
+// Resolved on the R's main thread when the first device is created, as the
+// font lookups happen with `FONTDB` locked. `None` inside if systemfonts is
+// not installed.
+static SYSTEM_FONTS: OnceCell<Option<SystemFonts>> = OnceCell::new();
+
+// Note that this must be called on the R's main thread.
+pub(crate) fn init_system_fonts() {
+    SYSTEM_FONTS.get_or_init(SystemFonts::new);
+}
+
+// Returns `None` if systemfonts is not installed, or `init_system_fonts()` is
+// not called yet (e.g. in the tests).
+pub(crate) fn system_fonts() -> Option<&'static SystemFonts> {
+    SYSTEM_FONTS.get()?.as_ref()
+}
+
+impl SystemFonts {
+    // Returns `None` if systemfonts is not installed. Note that this must be
+    // called on the R's main thread.
+    pub(crate) fn new() -> Option<Self> {
+        // The C-callables are registered when the namespace is loaded, so load
+        // it first. R_GetCCallable() raises an R error if it's not loaded.
+        let installed = eval_string("requireNamespace('systemfonts', quietly = TRUE)")
+            .ok()
+            .and_then(|x| x.as_bool())
+            .unwrap_or(false);
+
+        if !installed {
+            return None;
+        }
+
+        let package = CString::new("systemfonts").unwrap();
+        let name = CString::new("locate_font").unwrap();
+        let f = unsafe { libR_sys::R_GetCCallable(package.as_ptr(), name.as_ptr()) }?;
+
+        Some(Self {
+            locate_font: unsafe {
+                std::mem::transmute::<unsafe extern "C" fn() -> *mut c_void, LocateFontFn>(f)
+            },
+        })
+    }
+
+    // Returns the path to the font file and the index of the face in it.
+    pub(crate) fn locate(&self, family: &str, italic: bool, bold: bool) -> Option<(PathBuf, u32)> {
+        let family = CString::new(family).ok()?;
+        let mut path = vec![0 as c_char; MAX_PATH_LENGTH];
+
+        let index = unsafe {
+            (self.locate_font)(
+                family.as_ptr(),
+                italic as _,
+                bold as _,
+                path.as_mut_ptr(),
+                MAX_PATH_LENGTH as _,
+            )
+        };
+
+        let path = unsafe { CStr::from_ptr(path.as_ptr()) }.to_str().ok()?;
+        if path.is_empty() || index < 0 {
+            return None;
+        }
+
+        Some((PathBuf::from(path), index as _))
+    }
+}
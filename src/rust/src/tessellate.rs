@@ -0,0 +1,341 @@
+// Tessellation is the most expensive part of drawing polygons (e.g. a
+// choropleth map with thousands of regions), so it's not done on the R's
+// thread when the shapes are drawn. Instead, the paths are recorded as jobs,
+// and tessellated in parallel when the page is rendered. The results are then
+// merged into one vertex and index buffer in the original order.
+//
+// lyon's strokes overlap themselves at the joins and the self-intersections,
+// which makes a translucent stroke darker there. To draw each pixel only once,
+// every job gets its own depth, increasing in the drawing order. With the depth
+// test "greater", the triangles of the same job can't overwrite each other,
+// while the later jobs are drawn over the earlier ones as usual.
+
+use glam::f32::Affine2;
+use lyon::lyon_tessellation::VertexBuffers;
+use lyon::path::Path;
+use lyon::tessellation::geometry_builder::*;
+use lyon::tessellation::{FillOptions, FillTessellator, FillVertex};
+use lyon::tessellation::{StrokeOptions, StrokeTessellator, StrokeVertex};
+use rayon::prelude::*;
+
+use crate::batch::BoundingBox;
+use crate::Vertex;
+
+// The rough number of bytes per point of a path; the point itself, and the
+// vertices and the indices after tessellation.
+const ESTIMATED_BYTES_PER_POINT: usize = 64;
+
+// The number of jobs tessellated in a row on a thread. Most of the jobs are
+// small, so it's not worth spawning a task for every single job.
+const JOBS_PER_TASK: usize = 64;
+
+// The difference of the depths between the consecutive jobs. This is large
+// enough to survive the rounding on the interpolation of the depth.
+const DEPTH_STEP: f32 = 1.0 / (1 << 22) as f32;
+
+// The number of the jobs that can have distinct depths. The jobs need to be
+// drawn before exceeding this.
+pub(crate) const MAX_JOBS_PER_RENDER: usize = (1 << 22) - 1;
+
+// The depth of the i-th job. The depth buffer is cleared to 0, so this starts
+// from 1 step.
+fn job_depth(index: usize) -> f32 {
+    (index + 1) as f32 * DEPTH_STEP
+}
+
+pub(crate) enum TessellationShape {
+    Path(Path),
+    Rect(lyon::math::Rect),
+}
+
+pub(crate) enum TessellationStyle {
+    Fill(FillOptions),
+    Stroke(StrokeOptions),
+}
+
+pub(crate) struct TessellationJob {
+    pub(crate) shape: TessellationShape,
+    pub(crate) style: TessellationStyle,
+    pub(crate) color: i32,
+    pub(crate) transform: Affine2,
+}
+
+struct VertexCtor {
+    color: u32,
+    transform: Affine2,
+    depth: f32,
+}
+
+impl VertexCtor {
+    fn new(color: i32, transform: Affine2, depth: f32) -> Self {
+        Self {
+            color: unsafe { std::mem::transmute(color) },
+            transform,
+            depth,
+        }
+    }
+}
+
+impl StrokeVertexConstructor<Vertex> for VertexCtor {
+    fn new_vertex(&mut self, vertex: StrokeVertex) -> Vertex {
+        let position_orig: mint::Point2<_> = vertex.position().into();
+        let position = self.transform.transform_point2(position_orig.into());
+
+        Vertex {
+            position: position.into(),
+            color: self.color,
+            depth: self.depth,
+        }
+    }
+}
+
+impl FillVertexConstructor<Vertex> for VertexCtor {
+    fn new_vertex(&mut self, vertex: FillVertex) -> Vertex {
+        let position_orig: mint::Point2<_> = vertex.position().into();
+        let position = self.transform.transform_point2(position_orig.into());
+
+        Vertex {
+            position: position.into(),
+            color: self.color,
+            depth: self.depth,
+        }
+    }
+}
+
+impl TessellationJob {
+    pub(crate) fn bounds(&self) -> BoundingBox {
+        let bbox = match &self.shape {
+            TessellationShape::Path(path) => BoundingBox::from_points(path.iter().map(|event| {
+                let p = event.to();
+                (p.x, p.y)
+            })),
+            TessellationShape::Rect(rect) => BoundingBox {
+                min: [rect.min_x(), rect.min_y()],
+                max: [rect.max_x(), rect.max_y()],
+            },
+        };
+
+        // A mitre join can stick out by up to the half of the line width times
+        // the mitre limit, and a square cap by sqrt(2) times the half width.
+        let margin = match &self.style {
+            TessellationStyle::Fill(_) => 0.0,
+            TessellationStyle::Stroke(options) => {
+                options.line_width / 2.0 * options.miter_limit.max(std::f32::consts::SQRT_2)
+            }
+        };
+
+        // Plus 1 pixel for anti-aliasing
+        bbox.transform(self.transform).expand(margin + 1.0)
+    }
+
+    // The memory this job takes until it's drawn
+    pub(crate) fn estimated_bytes(&self) -> usize {
+        let n_points = match &self.shape {
+            TessellationShape::Path(path) => path.iter().count(),
+            TessellationShape::Rect(_) => 4,
+        };
+        n_points * ESTIMATED_BYTES_PER_POINT
+    }
+
+    // Appends the result to `geometry` and returns the number of the indices.
+    fn tessellate(
+        &self,
+        depth: f32,
+        fill_tess: &mut FillTessellator,
+        stroke_tess: &mut StrokeTessellator,
+        geometry: &mut VertexBuffers<Vertex, u32>,
+    ) -> u32 {
+        let ctxt = VertexCtor::new(self.color, self.transform, depth);
+        let builder = &mut BuffersBuilder::new(geometry, ctxt);
+
+        let result = match (&self.shape, &self.style) {
+            (TessellationShape::Path(path), TessellationStyle::Fill(options)) => {
+                fill_tess.tessellate_path(path, options, builder)
+            }
+            (TessellationShape::Path(path), TessellationStyle::Stroke(options)) => {
+                stroke_tess.tessellate_path(path, options, builder)
+            }
+            (TessellationShape::Rect(rect), TessellationStyle::Fill(options)) => {
+                fill_tess.tessellate_rectangle(rect, options, builder)
+            }
+            (TessellationShape::Rect(rect), TessellationStyle::Stroke(options)) => {
+                stroke_tess.tessellate_rectangle(rect, options, builder)
+            }
+        };
+
+        result.unwrap().indices
+    }
+}
+
+// Tessellates the jobs into `geometry`, and returns the offsets of the indices
+// of each job; the indices of the i-th job are `offsets[i]..offsets[i + 1]`.
+pub(crate) fn tessellate_jobs(
+    jobs: &[TessellationJob],
+    geometry: &mut VertexBuffers<Vertex, u32>,
+) -> Vec<u32> {
+    let chunks: Vec<(VertexBuffers<Vertex, u32>, Vec<u32>)> = jobs
+        .par_chunks(JOBS_PER_TASK)
+        .enumerate()
+        .map(|(chunk_index, chunk)| {
+            let mut fill_tess = FillTessellator::new();
+            let mut stroke_tess = StrokeTessellator::new();
+            let mut chunk_geometry = VertexBuffers::new();
+
+            let counts = chunk
+                .iter()
+                .enumerate()
+                .map(|(i, job)| {
+                    let depth = job_depth(chunk_index * JOBS_PER_TASK + i);
+                    job.tessellate(depth, &mut fill_tess, &mut stroke_tess, &mut chunk_geometry)
+                })
+                .collect();
+
+            (chunk_geometry, counts)
+        })
+        .collect();
+
+    geometry.vertices.clear();
+    geometry.indices.clear();
+
+    let mut offsets = Vec::with_capacity(jobs.len() + 1);
+    offsets.push(0);
+
+    for (chunk_geometry, counts) in chunks {
+        // The indices of each chunk start from 0, so shift them.
+        let base_vertex = geometry.vertices.len() as u32;
+        geometry.vertices.extend(chunk_geometry.vertices);
+        geometry
+            .indices
+            .extend(chunk_geometry.indices.iter().map(|i| i + base_vertex));
+
+        for count in counts {
+            offsets.push(offsets.last().unwrap() + count);
+        }
+    }
+
+    offsets
+}
+
+#[test]
+fn test_tessellate_jobs() {
+    // Polygons of different numbers of vertices
+    let jobs: Vec<TessellationJob> = (0..200)
+        .map(|i| {
+            let mut builder = Path::builder();
+            builder.begin(lyon::math::point(0.0, 0.0));
+            for j in 1..(3 + i % 5) {
+                let theta = j as f32;
+                builder.line_to(lyon::math::point(theta.cos() * 10.0, theta.sin() * 10.0));
+            }
+            builder.end(true);
+
+            let style = if i % 2 == 0 {
+                TessellationStyle::Fill(FillOptions::default())
+            } else {
+                TessellationStyle::Stroke(StrokeOptions::default())
+            };
+
+            TessellationJob {
+                shape: TessellationShape::Path(builder.build()),
+                style,
+                color: i,
+                transform: Affine2::from_translation(glam::Vec2::new(i as f32, 0.0)),
+            }
+        })
+        .collect();
+
+    let mut geometry = VertexBuffers::new();
+    let offsets = tessellate_jobs(&jobs, &mut geometry);
+    assert_eq!(offsets.len(), jobs.len() + 1);
+
+    // The same as tessellating one by one
+    let mut fill_tess = FillTessellator::new();
+    let mut stroke_tess = StrokeTessellator::new();
+    let mut expected = VertexBuffers::new();
+    for (i, job) in jobs.iter().enumerate() {
+        let count = job.tessellate(
+            job_depth(i),
+            &mut fill_tess,
+            &mut stroke_tess,
+            &mut expected,
+        );
+        assert_eq!(offsets[i + 1] - offsets[i], count);
+    }
+
+    assert_eq!(geometry.indices, expected.indices);
+    let colors = |g: &VertexBuffers<Vertex, u32>| -> Vec<(u32, [f32; 2], f32)> {
+        g.vertices
+            .iter()
+            .map(|v| (v.color, v.position, v.depth))
+            .collect()
+    };
+    assert_eq!(colors(&geometry), colors(&expected));
+
+    // The last job is still distinguishable from the previous one
+    let last = job_depth(MAX_JOBS_PER_RENDER - 1);
+    assert!(last > job_depth(MAX_JOBS_PER_RENDER - 2));
+    assert!(last < 1.0);
+}
+
+#[test]
+fn test_draw_once() {
+    use wgpu::util::DeviceExt;
+
+    use crate::gpu_test::{create_test_texture, draw_test, read_test_texture, test_gpu_context};
+
+    let gpu = match test_gpu_context() {
+        Some(gpu) => gpu,
+        None => return,
+    };
+
+    // A translucent stroke that crosses itself, with sharp joins
+    let mut builder = Path::builder();
+    builder.begin(lyon::math::point(10.0, 10.0));
+    builder.line_to(lyon::math::point(54.0, 54.0));
+    builder.line_to(lyon::math::point(54.0, 10.0));
+    builder.line_to(lyon::math::point(10.0, 54.0));
+    builder.end(false);
+
+    let job = TessellationJob {
+        shape: TessellationShape::Path(builder.build()),
+        style: TessellationStyle::Stroke(StrokeOptions::default().with_line_width(6.0)),
+        color: 0x80000000_u32 as i32,
+        transform: Affine2::IDENTITY,
+    };
+
+    let mut geometry = VertexBuffers::new();
+    tessellate_jobs(&[job], &mut geometry);
+
+    // Unindex the triangles
+    let vertices: Vec<Vertex> = geometry
+        .indices
+        .iter()
+        .map(|i| geometry.vertices[*i as usize])
+        .collect();
+    let vertex_buffer = gpu
+        .device
+        .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: None,
+            contents: bytemuck::cast_slice(&vertices),
+            usage: wgpu::BufferUsages::VERTEX,
+        });
+
+    let texture = create_test_texture(gpu, 1);
+    draw_test(
+        gpu,
+        &texture.create_view(&wgpu::TextureViewDescriptor::default()),
+        1,
+        1,
+        |p| &p.render_pipeline,
+        &[&vertex_buffer],
+        None,
+        vertices.len() as _,
+        1,
+    );
+    let image = read_test_texture(gpu, &texture, 1);
+
+    // Even where the triangles overlap, the stroke is drawn only once, i.e.
+    // 1 - 0.5.
+    let darkest = image.iter().cloned().fold(1.0, f32::min);
+    assert!(darkest > 0.5 - 0.02, "the darkest is {darkest}");
+}
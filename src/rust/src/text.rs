@@ -1,32 +1,32 @@
-use extendr_api::prelude::*;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::Mutex;
+
+use extendr_api::{graphics::TextMetric, prelude::*};
 use once_cell::sync::Lazy;
 
+use crate::stats;
+use crate::systemfonts::system_fonts;
+
 pub(crate) struct FontDBWrapper {
     db: fontdb::Database,
     fallback_glyph_id: Option<fontdb::ID>,
+    // The font files loaded on demand. The keys are the path and the face index.
+    loaded_faces: HashMap<(PathBuf, u32), fontdb::ID>,
 }
 
 impl FontDBWrapper {
-    pub(crate) fn query(&self, fontfamily: &str, fontface: i32) -> Option<fontdb::ID> {
-        // TODO: Can I do this more nicely?
-        let (weight, style) = match fontface {
-            1 => (fontdb::Weight::NORMAL, fontdb::Style::Normal), // Plain
-            2 => (fontdb::Weight::BOLD, fontdb::Style::Normal),   // Bold
-            3 => (fontdb::Weight::NORMAL, fontdb::Style::Italic), // Italic
-            4 => (fontdb::Weight::BOLD, fontdb::Style::Italic),   // BoldItalic
-            // Symbolic or unknown
-            _ => {
-                reprintln!("[WARN] Unsupported fontface");
-                (fontdb::Weight::NORMAL, fontdb::Style::Normal)
-            }
-        };
+    pub(crate) fn query(&mut self, spec: &FontSpec) -> Option<fontdb::ID> {
+        stats::FONT_QUERIES.incr();
 
-        if let Some(id) = self.db.query(&fontdb::Query {
-            families: &[fontdb::Family::Name(fontfamily)],
-            weight,
-            stretch: fontdb::Stretch::Normal,
-            style,
-        }) {
+        // If systemfonts is available, it takes precedence so that the font
+        // resolution is consistent with other devices.
+        if let Some(id) = self.query_systemfonts(spec) {
+            return Some(id);
+        }
+
+        if let Some(id) = self.query_fontdb(&spec.family, spec) {
             Some(id)
         } else {
             // TODO: This warning is shown too many times, so disabled temporarily...
@@ -38,6 +38,61 @@ impl FontDBWrapper {
         }
     }
 
+    fn query_fontdb(&self, family: &str, spec: &FontSpec) -> Option<fontdb::ID> {
+        self.db.query(&fontdb::Query {
+            families: &[fontdb::Family::Name(family)],
+            weight: fontdb::Weight(spec.weight),
+            stretch: spec.stretch(),
+            style: if spec.italic {
+                fontdb::Style::Italic
+            } else {
+                fontdb::Style::Normal
+            },
+        })
+    }
+
+    fn query_systemfonts(&mut self, spec: &FontSpec) -> Option<fontdb::ID> {
+        let (path, index) =
+            system_fonts()?.locate(&spec.family, spec.italic, spec.weight >= 600)?;
+
+        let id = match self.loaded_faces.get(&(path.clone(), index)) {
+            Some(id) => *id,
+            None => {
+                let id = self.load_face(&path, index)?;
+                self.loaded_faces.insert((path, index), id);
+                id
+            }
+        };
+
+        // systemfonts only knows regular and bold. If other weights or widths
+        // are requested, look for a better face of the same family among the
+        // faces we know. (For variable fonts, the axes are set later, so the
+        // face found here is fine.)
+        if !matches!(spec.weight, 400 | 700) || spec.stretch() != fontdb::Stretch::Normal {
+            let family = self.db.face(id)?.family.clone();
+            if let Some(better_id) = self.query_fontdb(&family, spec) {
+                return Some(better_id);
+            }
+        }
+
+        Some(id)
+    }
+
+    fn load_face(&mut self, path: &std::path::Path, index: u32) -> Option<fontdb::ID> {
+        let n_faces = self.db.faces().len();
+
+        if let Err(e) = self.db.load_font_file(path) {
+            reprintln!("[WARN] Failed to load the font file {path:?}: {e}");
+            return None;
+        }
+
+        // The faces in the file are appended to the end
+        self.db.faces()[n_faces..]
+            .iter()
+            .find(|f| f.index == index)
+            .map(|f| f.id)
+    }
+
     pub(crate) fn with_face_data<P, T>(&self, id: fontdb::ID, p: P) -> Option<T>
     where
         P: FnOnce(&[u8], u32) -> T,
@@ -46,7 +101,8 @@ impl FontDBWrapper {
     }
 }
 
-pub(crate) static FONTDB: Lazy<FontDBWrapper> = Lazy::new(|| {
+// This is wrapped with Mutex because font files can be loaded on demand.
+pub(crate) static FONTDB: Lazy<Mutex<FontDBWrapper>> = Lazy::new(|| {
     let mut db = fontdb::Database::new();
     db.load_system_fonts();
 
@@ -55,18 +111,347 @@ pub(crate) static FONTDB: Lazy<FontDBWrapper> = Lazy::new(|| {
         ..Default::default()
     });
 
-    FontDBWrapper {
+    Mutex::new(FontDBWrapper {
         db,
         fallback_glyph_id,
-    }
+        loaded_faces: HashMap::new(),
+    })
 });
 
+// R only tells us the family name and one of the four fontfaces, which is not
+// enough to choose semi-bold, light, or condensed styles, or to pick an instance
+// of a variable font. So, we allow the family name to carry additional font
+// properties after colons, e.g. "Roboto Flex:wght=550:wdth=75".
+//
+// The recognized keys are:
+//
+// * `weight` or `wght`: a numeric weight (1-1000) or a name like `semibold`
+// * `width` or `wdth`: a width in percent (50-200) or a name like `condensed`
+// * any other 4-letter OpenType axis tag (e.g. `opsz`, `slnt`, `GRAD`)
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct FontSpec {
+    pub(crate) family: String,
+    pub(crate) weight: u16,
+    // in percent, as the `wdth` axis is
+    pub(crate) width: f32,
+    pub(crate) italic: bool,
+    // Custom axis coordinates other than `wght` and `wdth`
+    pub(crate) variations: Vec<(ttf_parser::Tag, f32)>,
+}
+
+impl FontSpec {
+    // Also returns the warnings on the font properties. They are not printed
+    // here so that the caller can report them only once per family string.
+    pub(crate) fn parse(fontfamily: &str, fontface: i32) -> (Self, Vec<String>) {
+        let mut warnings = Vec::new();
+
+        // TODO: Can I do this more nicely?
+        let (bold, italic) = match fontface {
+            1 => (false, false), // Plain
+            2 => (true, false),  // Bold
+            3 => (false, true),  // Italic
+            4 => (true, true),   // BoldItalic
+            // Symbolic or unknown
+            _ => {
+                warnings.push(format!("Unsupported fontface: {fontface}"));
+                (false, false)
+            }
+        };
+
+        let mut parts = fontfamily.split(':');
+        // split() always yields at least one element
+        let family = parts.next().unwrap_or_default().trim().to_string();
+
+        let mut spec = Self {
+            family,
+            weight: if bold { 700 } else { 400 },
+            width: 100.0,
+            italic,
+            variations: Vec::new(),
+        };
+
+        for part in parts {
+            let (key, value) = match part.split_once('=') {
+                Some((k, v)) => (k.trim(), v.trim()),
+                None => {
+                    warnings.push(format!("Ignoring malformed font property: {part}"));
+                    continue;
+                }
+            };
+
+            match key {
+                "weight" | "wght" => match parse_weight(value) {
+                    Some(w) => spec.weight = w,
+                    None => warnings.push(format!("Invalid font weight: {value}")),
+                },
+                "width" | "wdth" => match parse_width(value) {
+                    Some(w) => spec.width = w,
+                    None => warnings.push(format!("Invalid font width: {value}")),
+                },
+                _ if key.len() == 4 && key.is_ascii() => match value.parse::<f32>() {
+                    Ok(v) => {
+                        let tag = ttf_parser::Tag::from_bytes_lossy(key.as_bytes());
+                        spec.variations.push((tag, v));
+                    }
+                    Err(_) => {
+                        warnings.push(format!("Invalid value for the font axis {key}: {value}"))
+                    }
+                },
+                _ => warnings.push(format!("Unknown font property: {key}")),
+            }
+        }
+
+        (spec, warnings)
+    }
+
+    // fontdb only knows the nine predefined widths, so choose the nearest one.
+    pub(crate) fn stretch(&self) -> fontdb::Stretch {
+        match self.width {
+            w if w < 56.25 => fontdb::Stretch::UltraCondensed,
+            w if w < 68.75 => fontdb::Stretch::ExtraCondensed,
+            w if w < 81.25 => fontdb::Stretch::Condensed,
+            w if w < 93.75 => fontdb::Stretch::SemiCondensed,
+            w if w < 106.25 => fontdb::Stretch::Normal,
+            w if w < 118.75 => fontdb::Stretch::SemiExpanded,
+            w if w < 137.5 => fontdb::Stretch::Expanded,
+            w if w < 175.0 => fontdb::Stretch::ExtraExpanded,
+            _ => fontdb::Stretch::UltraExpanded,
+        }
+    }
+
+    // Set the axis coordinates of a variable font. For a non-variable font,
+    // this does nothing; the closest static face is already chosen on query.
+    pub(crate) fn apply_variations(&self, face: &mut ttf_parser::Face) {
+        if !face.is_variable() {
+            return;
+        }
+
+        // set_variation() returns None if the face doesn't have the axis, which
+        // is fine to ignore here.
+        let _ = face.set_variation(ttf_parser::Tag::from_bytes(b"wght"), self.weight as _);
+        let _ = face.set_variation(ttf_parser::Tag::from_bytes(b"wdth"), self.width);
+        if self.italic {
+            let _ = face.set_variation(ttf_parser::Tag::from_bytes(b"ital"), 1.0);
+        }
+
+        for (tag, value) in &self.variations {
+            let _ = face.set_variation(*tag, *value);
+        }
+    }
+
+    // The custom axes that the face doesn't have
+    fn missing_axes(&self, face: &ttf_parser::Face) -> Vec<ttf_parser::Tag> {
+        self.variations
+            .iter()
+            .map(|(tag, _)| *tag)
+            .filter(|tag| {
+                !face
+                    .variation_axes()
+                    .into_iter()
+                    .any(|axis| axis.tag == *tag)
+            })
+            .collect()
+    }
+}
+
+fn parse_weight(x: &str) -> Option<u16> {
+    let weight = match x.to_ascii_lowercase().replace(['-', '_', ' '], "").as_str() {
+        "thin" | "hairline" => 100,
+        "extralight" | "ultralight" => 200,
+        "light" => 300,
+        "normal" | "regular" => 400,
+        "medium" => 500,
+        "semibold" | "demibold" => 600,
+        "bold" => 700,
+        "extrabold" | "ultrabold" => 800,
+        "black" | "heavy" => 900,
+        _ => x.parse::<f32>().ok()?.round() as _,
+    };
+
+    if (1..=1000).contains(&weight) {
+        Some(weight)
+    } else {
+        None
+    }
+}
+
+fn parse_width(x: &str) -> Option<f32> {
+    let width = match x.to_ascii_lowercase().replace(['-', '_', ' '], "").as_str() {
+        "ultracondensed" => 50.0,
+        "extracondensed" => 62.5,
+        "condensed" => 75.0,
+        "semicondensed" => 87.5,
+        "normal" => 100.0,
+        "semiexpanded" => 112.5,
+        "expanded" => 125.0,
+        "extraexpanded" => 150.0,
+        "ultraexpanded" => 200.0,
+        _ => x.trim_end_matches('%').parse::<f32>().ok()?,
+    };
+
+    if width > 0.0 {
+        Some(width)
+    } else {
+        None
+    }
+}
+
+// Parse the face and set the axis coordinates according to the spec.
+pub(crate) fn parse_face<'a>(
+    font_data: &'a [u8],
+    face_index: u32,
+    spec: &FontSpec,
+) -> Option<ttf_parser::Face<'a>> {
+    let mut face = ttf_parser::Face::from_slice(font_data, face_index).ok()?;
+    spec.apply_variations(&mut face);
+    Some(face)
+}
+
+// Unrotated texts are placed on 1/SUBPIXEL_STEPS of a pixel, so that the same
+// string always looks the same wherever it's placed.
+pub(crate) const SUBPIXEL_STEPS: f32 = 4.0;
+// Below this size (in pixels), the stems are thickened a bit, like hinting does,
+// so that small texts don't look too thin and faint.
+pub(crate) const STEM_DARKENING_MAX_SIZE: f32 = 10.0;
+// How much the stems are thickened (in pixels)
+pub(crate) const STEM_DARKENING: f32 = 0.3;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum TextMode {
+    // Tessellate the outline of the glyphs
+    Outline,
+    // Render the glyphs from the SDF atlas
+    Atlas,
+}
+
+pub(crate) struct CachedFace {
+    // A serial number to distinguish the faces in the cache. Note that the
+    // fontdb ID is not enough because the same face can be used with different
+    // variations.
+    pub(crate) key: usize,
+    pub(crate) id: fontdb::ID,
+    pub(crate) spec: FontSpec,
+}
+
+// R calls char_metric() for every character of every label to layout a plot,
+// and it's mostly with the same arguments. So, cache the font lookups and the
+// metrics for the lifetime of the device.
+#[derive(Default)]
+pub(crate) struct FontCache {
+    // fontface -> fontfamily -> face
+    faces: HashMap<i32, HashMap<String, Option<Rc<CachedFace>>>>,
+    // (key of the face, character, size) -> (ascent, descent, width)
+    metrics: HashMap<(usize, char, u64), (f64, f64, f64)>,
+    n_faces: usize,
+    // The warnings already printed with the family strings, so that a malformed
+    // one doesn't flood the console (the face is looked up for each fontface).
+    warned: HashSet<(String, String)>,
+}
+
+impl FontCache {
+    pub(crate) fn face(&mut self, fontfamily: &str, fontface: i32) -> Option<Rc<CachedFace>> {
+        if let Some(cached) = self
+            .faces
+            .get(&fontface)
+            .and_then(|faces| faces.get(fontfamily))
+        {
+            return cached.clone();
+        }
+
+        let (spec, mut warnings) = FontSpec::parse(fontfamily, fontface);
+        let id = FONTDB.lock().unwrap().query(&spec);
+
+        if let Some(id) = id {
+            let missing_axes = FONTDB
+                .lock()
+                .unwrap()
+                .with_face_data(id, |font_data, face_index| {
+                    ttf_parser::Face::from_slice(font_data, face_index)
+                        .map(|face| spec.missing_axes(&face))
+                        .unwrap_or_default()
+                })
+                .unwrap_or_default();
+            for tag in missing_axes {
+                warnings.push(format!("The font doesn't have the axis {tag}"));
+            }
+        }
+
+        for warning in warnings {
+            if self
+                .warned
+                .insert((fontfamily.to_string(), warning.clone()))
+            {
+                reprintln!("[WARN] {warning}");
+            }
+        }
+
+        let face = id.map(|id| {
+            self.n_faces += 1;
+            Rc::new(CachedFace {
+                key: self.n_faces,
+                id,
+                spec,
+            })
+        });
+
+        self.faces
+            .entry(fontface)
+            .or_default()
+            .insert(fontfamily.to_string(), face.clone());
+
+        face
+    }
+
+    pub(crate) fn metric(&mut self, face: &CachedFace, c: char, size: f64) -> TextMetric {
+        let (ascent, descent, width) = *self
+            .metrics
+            .entry((face.key, c, size.to_bits()))
+            .or_insert_with(|| {
+                stats::GLYPH_METRIC_LOOKUPS.incr();
+                glyph_metric(face, c, size)
+            });
+
+        TextMetric {
+            ascent,
+            descent,
+            width,
+        }
+    }
+}
+
+fn glyph_metric(face: &CachedFace, c: char, size: f64) -> (f64, f64, f64) {
+    FONTDB
+        .lock()
+        .unwrap()
+        .with_face_data(face.id, |font_data, face_index| {
+            let font = parse_face(font_data, face_index, &face.spec).unwrap();
+            let scale = size / font.height() as f64;
+
+            let glyph_id = font.glyph_index(c).unwrap_or(ttf_parser::GlyphId(0));
+
+            match font.glyph_bounding_box(glyph_id) {
+                Some(bbox) => (
+                    bbox.y_max as f64 * scale,
+                    bbox.y_min as f64 * scale,
+                    font.glyph_hor_advance(glyph_id)
+                        .unwrap_or(bbox.width() as _) as f64
+                        * scale,
+                ),
+                // If the glyph info is not available, use font info
+                _ => (
+                    font.ascender() as f64 * scale,
+                    font.descender() as f64 * scale,
+                    font.height() as f64 * scale,
+                ),
+            }
+        })
+        .unwrap()
+}
+
 pub(crate) struct LyonOutlineBuilder {
     pub(crate) builder: lyon::path::path::Builder,
     // multiply by this to scale the position into the range of [0, 1].
     scale_factor: f32,
-
-    offset_x: f32,
 }
 
 impl LyonOutlineBuilder {
@@ -74,7 +459,6 @@ impl LyonOutlineBuilder {
         Self {
             builder: lyon::path::Path::builder(),
             scale_factor: scale,
-            offset_x: 0.0,
         }
     }
 
@@ -83,15 +467,7 @@ impl LyonOutlineBuilder {
     }
 
     fn point(&self, x: f32, y: f32) -> lyon::math::Point {
-        lyon::math::point(x * self.scale_factor + self.offset_x, y * self.scale_factor)
-    }
-
-    pub(crate) fn add_offset_x(&mut self, offset: f32) {
-        self.offset_x += offset * self.scale_factor;
-    }
-
-    pub(crate) fn offset_x(&self) -> f32 {
-        self.offset_x
+        lyon::math::point(x * self.scale_factor, y * self.scale_factor)
     }
 }
 
@@ -122,6 +498,58 @@ impl ttf_parser::OutlineBuilder for LyonOutlineBuilder {
     }
 }
 
+// Lays out the glyphs of a line of text. Returns the glyphs with their offsets
+// and the total advance, in the font units.
+pub(crate) fn layout_glyphs(
+    font: &ttf_parser::Face,
+    text: &str,
+) -> (Vec<(ttf_parser::GlyphId, f32)>, f32) {
+    let mut glyphs = Vec::with_capacity(text.len());
+    let mut offset_x = 0.0_f32;
+
+    let mut prev_glyph: Option<ttf_parser::GlyphId> = None;
+    for c in text.chars() {
+        // Skip control characters. Note that it seems linebreaks are handled
+        // on R's side, so we don't need to care about multiline cases.
+        if c.is_control() {
+            prev_glyph = None;
+            continue;
+        }
+
+        // Even when we cannot find glyph_id, fill it with 0.
+        let cur_glyph = font.glyph_index(c).unwrap_or(ttf_parser::GlyphId(0));
+
+        if let Some(prev_glyph) = prev_glyph {
+            offset_x += find_kerning(font.tables(), prev_glyph, cur_glyph) as f32;
+        }
+
+        glyphs.push((cur_glyph, offset_x));
+
+        if let Some(ha) = font.glyph_hor_advance(cur_glyph) {
+            offset_x += ha as f32;
+        }
+
+        prev_glyph = Some(cur_glyph);
+    }
+
+    (glyphs, offset_x)
+}
+
+// The vertical scale to make the x-height (in pixels) a whole number of pixels
+pub(crate) fn x_height_scale(x_height: f32) -> f32 {
+    if x_height >= 1.0 {
+        x_height.round() / x_height
+    } else {
+        1.0
+    }
+}
+
+// Places the origin of a glyph on 1/SUBPIXEL_STEPS of a pixel horizontally, and
+// the baseline on the pixel boundary.
+pub(crate) fn snap_glyph_origin(x: f32, y: f32) -> glam::Vec2 {
+    glam::vec2((x * SUBPIXEL_STEPS).round() / SUBPIXEL_STEPS, y.round())
+}
+
 pub(crate) fn find_kerning(
     facetables: &ttf_parser::FaceTables,
     left: ttf_parser::GlyphId,
@@ -145,3 +573,119 @@ pub(crate) fn find_kerning(
 
     0
 }
+
+#[test]
+fn test_font_spec() {
+    let (spec, _) = FontSpec::parse("Roboto Flex", 1);
+    assert_eq!(spec.family, "Roboto Flex");
+    assert_eq!(spec.weight, 400);
+    assert_eq!(spec.width, 100.0);
+    assert!(!spec.italic);
+    assert!(spec.variations.is_empty());
+
+    // fontface is respected
+    let (spec, _) = FontSpec::parse("Roboto Flex", 4);
+    assert_eq!(spec.weight, 700);
+    assert!(spec.italic);
+
+    // numeric weight and width, and a custom axis
+    let (spec, _) = FontSpec::parse("Roboto Flex:wght=550:wdth=75:opsz=14", 1);
+    assert_eq!(spec.family, "Roboto Flex");
+    assert_eq!(spec.weight, 550);
+    assert_eq!(spec.width, 75.0);
+    assert_eq!(spec.stretch(), fontdb::Stretch::Condensed);
+    assert_eq!(
+        spec.variations,
+        vec![(ttf_parser::Tag::from_bytes(b"opsz"), 14.0)]
+    );
+
+    // named weight and width override the fontface
+    let (spec, _) = FontSpec::parse("Inter:weight=semibold:width=semi-expanded", 2);
+    assert_eq!(spec.weight, 600);
+    assert_eq!(spec.width, 112.5);
+    assert_eq!(spec.stretch(), fontdb::Stretch::SemiExpanded);
+
+    // malformed properties are ignored with warnings
+    let (spec, warnings) = FontSpec::parse("Inter:bold:wght=abc:foo=1", 1);
+    assert_eq!(spec.family, "Inter");
+    assert_eq!(spec.weight, 400);
+    assert_eq!(warnings.len(), 3);
+
+    // symbolic fonts are not supported
+    let (spec, warnings) = FontSpec::parse("Inter", 5);
+    assert_eq!(spec.weight, 400);
+    assert_eq!(warnings, vec!["Unsupported fontface: 5".to_string()]);
+}
+
+#[test]
+fn test_parse_weight() {
+    assert_eq!(parse_weight("100"), Some(100));
+    assert_eq!(parse_weight("Light"), Some(300));
+    assert_eq!(parse_weight("extra-bold"), Some(800));
+    assert_eq!(parse_weight("0"), None);
+    assert_eq!(parse_weight("heavier"), None);
+}
+
+// The data of a font on the system (preferably sans-serif), for the tests that
+// need a real font. The tests are skipped if there's none.
+#[cfg(test)]
+pub(crate) fn test_font_data() -> Option<Vec<u8>> {
+    let mut db = fontdb::Database::new();
+    db.load_system_fonts();
+    // Without fontconfig, the generic family may not resolve; any font will do.
+    let id = db
+        .query(&fontdb::Query {
+            families: &[fontdb::Family::SansSerif],
+            ..Default::default()
+        })
+        .or_else(|| db.faces().first().map(|face| face.id));
+    match id.and_then(|id| db.with_face_data(id, |data, _| data.to_vec())) {
+        Some(data) => Some(data),
+        None => {
+            eprintln!("Skipped the test: no font is found");
+            None
+        }
+    }
+}
+
+#[test]
+fn test_text_snapping() {
+    // 5.4px of x-height is scaled to 5px
+    assert_eq!(x_height_scale(5.4) * 5.4, 5.0);
+    assert_eq!(x_height_scale(0.5), 1.0);
+
+    assert_eq!(snap_glyph_origin(10.3, 20.6), glam::vec2(10.25, 21.0));
+    assert_eq!(snap_glyph_origin(10.4, 20.4), glam::vec2(10.5, 20.0));
+}
+
+#[test]
+fn test_layout_glyphs() {
+    let font_data = match test_font_data() {
+        Some(font_data) => font_data,
+        None => return,
+    };
+    let font = ttf_parser::Face::from_slice(&font_data, 0).unwrap();
+
+    let advance = |c: char| {
+        font.glyph_hor_advance(font.glyph_index(c).unwrap())
+            .unwrap() as f32
+    };
+    let kerning = |a: char, b: char| {
+        find_kerning(
+            font.tables(),
+            font.glyph_index(a).unwrap(),
+            font.glyph_index(b).unwrap(),
+        ) as f32
+    };
+
+    let (glyphs, width) = layout_glyphs(&font, "AV");
+    assert_eq!(glyphs.len(), 2);
+    assert_eq!(glyphs[0].1, 0.0);
+    assert_eq!(glyphs[1].1, advance('A') + kerning('A', 'V'));
+    assert_eq!(width, advance('A') + kerning('A', 'V') + advance('V'));
+
+    // Control characters are skipped, and break the kerning
+    let (glyphs, width) = layout_glyphs(&font, "A\tV");
+    assert_eq!(glyphs.len(), 2);
+    assert_eq!(width, advance('A') + advance('V'));
+}